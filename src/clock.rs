@@ -0,0 +1,81 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of the current time for expiries and write timestamps. Stores use
+/// `SystemClock` unless given another through `StorageConfig::clock` or
+/// `KvStore::with_clock`, e.g. a `ManualClock` in tests.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system's wall clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to, for testing expiry without
+/// sleeping. It may be set before the Unix epoch, which stores treat as
+/// the epoch itself.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<SystemTime>,
+}
+
+impl ManualClock {
+    pub fn new(now: SystemTime) -> Self {
+        ManualClock { now: Mutex::new(now) }
+    }
+
+    /// A clock reading `millis` milliseconds after the Unix epoch.
+    pub fn at_millis(millis: u64) -> Self {
+        Self::new(UNIX_EPOCH + Duration::from_millis(millis))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(|e| e.into_inner());
+        *now += by;
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner()) = now;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Milliseconds since the Unix epoch, clamped to 0 if the clock reads before
+// 1970 rather than panicking
+pub(crate) fn millis_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_moves_only_when_told() {
+        let clock = ManualClock::at_millis(1_000);
+        assert_eq!(millis_since_epoch(clock.now()), 1_000);
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(millis_since_epoch(clock.now()), 1_500);
+        clock.set(UNIX_EPOCH);
+        assert_eq!(millis_since_epoch(clock.now()), 0);
+    }
+
+    #[test]
+    fn times_before_the_epoch_clamp_to_zero() {
+        assert_eq!(millis_since_epoch(UNIX_EPOCH - Duration::from_secs(86_400)), 0);
+    }
+}
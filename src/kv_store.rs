@@ -1,155 +1,4772 @@
-use std::collections::HashMap;
-use std::fs;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::process;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use serde::{Serialize, Deserialize};
+use crate::clock::{self, Clock, SystemClock};
+use crate::merkle::{self, MerkleProof};
+use crate::types::StorageConfig;
 use std::error::Error;
+use std::fmt;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// Binary snapshots start with this magic followed by a one-byte format version.
+// Bincode is not self-describing, so the version must be bumped whenever the
+// layout of `DiskSnapshot` or `Value` changes.
+const BINARY_MAGIC: &[u8; 4] = b"KVSB";
+const BINARY_VERSION: u8 = 1;
+
+// Makes temp file names unique across saves within a process; the pid in
+// the name separates processes
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Temp files younger than this may belong to a save still in progress in
+// another process, so they are never treated as orphaned
+const STALE_TEMP_FILE_AGE: Duration = Duration::from_secs(60);
+
+// With save coalescing on, saves this soon after the last write are deferred
+const SAVE_COALESCE_WINDOW: Duration = Duration::from_millis(1);
+
+// Passes writes through to `inner`, counting the bytes accepted
+struct CountingWriter<W> {
+    inner: W,
+    written: usize,
+}
+
+impl<W> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        CountingWriter { inner, written: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// On-disk encoding used when saving the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageFormat {
+    #[default]
+    Json,
+    Bincode,
+}
+
+impl StorageFormat {
+    // Name of the store file in its directory; each format has its own file
+    pub(crate) fn file_name(self) -> &'static str {
+        match self {
+            StorageFormat::Json => "kv_store.json",
+            StorageFormat::Bincode => "kv_store.bin",
+        }
+    }
+}
+
+/// How expiry times are written to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TtlMode {
+    /// Unix timestamps, exactly as held in memory.
+    #[default]
+    Absolute,
+    /// Milliseconds remaining when the snapshot was written. Loading restarts
+    /// them against the loader's clock, so time between the save and the load
+    /// does not count against them.
+    Relative,
+}
+
+// Generic over the map type so saves can use a `BTreeMap` for sorted output;
+// both map types share the same serialized form
+#[derive(Serialize, Deserialize)]
+enum DiskSnapshot<M = HashMap<String, Value>> {
+    Absolute(M),
+    Relative(M),
+}
+
+// JSON form of a relative snapshot; absolute snapshots stay a bare map so
+// files written before TTL modes existed remain readable. The string tag
+// cannot be mistaken for an entry, which is always an object
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RelativeSnapshot<M = HashMap<String, Value>> {
+    ttl_mode: RelativeTag,
+    entries: M,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RelativeTag {
+    Relative,
+}
+
+/// Point-in-time copy of the live entries, used as a baseline for `diff_from`.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    entries: HashMap<String, String>,
+}
+
+/// Keys that differ between a `Snapshot` and the current store, each sorted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoreDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+/// JSON type of a stored value. Values that are not valid JSON count as
+/// `String`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    Null,
+    Bool,
+    Number,
+    String,
+    Array,
+    Object,
+}
+
+impl ValueType {
+    pub fn of(value: &serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => ValueType::Null,
+            serde_json::Value::Bool(_) => ValueType::Bool,
+            serde_json::Value::Number(_) => ValueType::Number,
+            serde_json::Value::String(_) => ValueType::String,
+            serde_json::Value::Array(_) => ValueType::Array,
+            serde_json::Value::Object(_) => ValueType::Object,
+        }
+    }
+}
+
+/// One row of `detailed_list`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EntryDetail {
+    pub key: String,
+    pub value: serde_json::Value,
+    pub value_type: ValueType,
+    /// Remaining TTL in seconds, or `None` for keys that never expire.
+    pub ttl: Option<u64>,
+}
+
+/// A value together with its metadata, as returned by `get_entry`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryInfo {
+    pub value: String,
+    /// Remaining TTL in seconds, or `None` for keys that never expire.
+    pub ttl: Option<u64>,
+    pub value_type: ValueType,
+    /// Write order of the entry, as used by `recent_keys`.
+    pub seq: u64,
+}
+
+/// Aggregates over the numeric values under a prefix.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NumericSummary {
+    pub count: usize,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+/// What happened to the on-disk entries when the store was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    /// Entries read from the file, including ones later dropped as expired.
+    pub loaded: usize,
+    /// Entries dropped because they had already expired.
+    pub expired: usize,
+    /// Entries skipped because they did not have the shape of a `Value`.
+    /// Always zero unless `StorageConfig::salvage_malformed` is set, since
+    /// otherwise a malformed entry fails the open.
+    pub malformed: usize,
+}
+
+/// Whether the file on disk reflects the in-memory store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncStatus {
+    Synced,
+    /// Changes are held in memory on purpose, by autosave being off, save
+    /// coalescing, or counter buffering, until `flush` or a later save.
+    Pending,
+    /// The last save failed with this error; changes since then are only in
+    /// memory until a later save or `flush` succeeds.
+    Unsynced(String),
+}
+
+/// One change published by `change_feed`, for a follower to replay with
+/// `apply_change`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Change {
+    /// `expires_at` is in milliseconds since the Unix epoch, as in `Value`.
+    Set { key: String, value: String, expires_at: Option<u64> },
+    Delete { key: String },
+    /// The key's value is unchanged but its expiry was changed.
+    Expire { key: String, expires_at: Option<u64> },
+}
+
+// Sender side of a change feed, plus the state last published through it
+#[derive(Debug)]
+struct ChangeFeed {
+    sender: Sender<Change>,
+    published: HashMap<String, (String, Option<u64>)>,
+}
+
+/// Which side wins when `reload_merging` finds a key both in memory and on
+/// disk. Keys present on only one side are kept, apart from keys deleted in
+/// memory since the last save.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Keep the in-memory value.
+    KeepExisting,
+    /// Take the value from disk.
+    PreferDisk,
+}
+
+/// Result of checking a store file with `KvStore::verify_file`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Whether the file parses as a snapshot at all.
+    pub well_formed: bool,
+    /// Entries found, including malformed ones.
+    pub entries: usize,
+    /// Keys whose entries do not have the shape of a `Value`, sorted.
+    pub malformed: Vec<String>,
+}
+
+/// Which end of a value `truncate_value_keeping` keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeepEnd {
+    #[default]
+    Head,
+    Tail,
+}
+
+/// One column of `export_csv_projected`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Column {
+    Key,
+    Value,
+    /// Remaining TTL in seconds; empty for keys that never expire.
+    Ttl,
+    /// The field at a dotted path inside a JSON value, e.g. `user.name` or
+    /// `items.0`; empty when the value has no such field.
+    JsonPath(String),
+}
+
+/// Order of the entries returned by `query`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuerySort {
+    #[default]
+    Key,
+    /// Soonest to expire first; keys without a TTL last, by key.
+    Ttl,
+}
+
+/// Filters, order, and page for `query`. The default matches everything,
+/// sorted by key, with no limit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryOptions {
+    /// Only keys starting with this prefix.
+    pub prefix: Option<String>,
+    /// Only values containing this substring.
+    pub value_contains: Option<String>,
+    pub sort: QuerySort,
+    /// Matching entries to skip before the page starts.
+    pub offset: usize,
+    /// Most entries to return; `None` returns the rest.
+    pub limit: Option<usize>,
+}
+
+/// One page of `query` results.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryResult {
+    pub entries: Vec<(String, String)>,
+    /// Entries matching the filters, across all pages.
+    pub total: usize,
+}
+
+/// Returned when a write would store a key longer than the limit set with
+/// `KvStore::with_max_key_bytes`. Downcast the boxed error to tell it apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyTooLong {
+    /// Length of the rejected key in bytes.
+    pub len: usize,
+    pub max: usize,
+}
+
+impl fmt::Display for KeyTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "key is {} bytes, longer than the {}-byte limit", self.len, self.max)
+    }
+}
+
+impl Error for KeyTooLong {}
+
+/// Prefix of the keys macros are stored under; see `KvStore::define_macro`.
+pub const MACRO_PREFIX: &str = "__macro__:";
+
+/// Returned when an ordinary write names a key under `MACRO_PREFIX`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReservedKey {
+    pub key: String,
+}
+
+impl fmt::Display for ReservedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "key '{}' is reserved: keys starting with '{}' hold macros", self.key, MACRO_PREFIX)
+    }
+}
+
+impl Error for ReservedKey {}
+
+/// Transforms values on their way into and out of a store, e.g. to compress
+/// them. Values are held and saved in encoded form and decoded only by the
+/// methods that return or inspect them, so key-only listings such as `keys`
+/// never decode. `decode` must accept anything `encode` returned.
+pub trait ValueCodec: fmt::Debug + Send + Sync {
+    fn encode(&self, value: &str) -> String;
+    fn decode(&self, encoded: &str) -> String;
+}
+
+// Macro keys are kept out of everything that lists, counts or exports keys
+fn is_reserved(key: &str) -> bool {
+    key.starts_with(MACRO_PREFIX)
+}
+
+/// How a key set through `set_with_policy` expires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpiryPolicy {
+    /// Expires a fixed number of seconds after being set.
+    Fixed(u64),
+    /// Expires after this many seconds without being read; each `get`
+    /// pushes the expiry back.
+    Sliding(u64),
+    Never,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Value {
     pub data: String,
+    /// Expiry in milliseconds since the Unix epoch. Files written before
+    /// millisecond TTLs stored seconds under `expires_at`; those are
+    /// converted on load.
+    #[serde(rename = "expires_at_ms")]
     pub expires_at: Option<u64>,
+    /// Write order of this entry; higher means more recently set.
+    #[serde(default)]
+    pub seq: u64,
+    /// Sliding expiry window in seconds, if reads extend this key's lifetime.
+    #[serde(default)]
+    pub sliding_ttl: Option<u64>,
+    /// Earlier values of this key, oldest first, if history is kept.
+    #[serde(default)]
+    pub history: Vec<String>,
+    /// Failures recorded against this entry by `fail`.
+    #[serde(default)]
+    pub attempts: u32,
+    /// When this entry's value was last written, in milliseconds since the
+    /// Unix epoch. 0 for entries loaded from files that predate tracking.
+    #[serde(default)]
+    pub updated_at: u64,
+    // Last read time of a sliding key. Atomic so `get` can extend expiry
+    // through `&self`; folded into `expires_at` whenever the store is saved.
+    #[serde(skip)]
+    touched_at: AtomicU64,
+}
+
+// Borrowed form of `Value` written by saves, so snapshots can be serialized
+// without cloning every value. Must serialize exactly like `Value`.
+#[derive(Serialize)]
+struct SavedValue<'a> {
+    data: &'a str,
+    #[serde(rename = "expires_at_ms")]
+    expires_at: Option<u64>,
+    seq: u64,
+    sliding_ttl: Option<u64>,
+    history: &'a [String],
+    attempts: u32,
+    updated_at: u64,
+}
+
+impl Clone for Value {
+    fn clone(&self) -> Self {
+        Value {
+            data: self.data.clone(),
+            expires_at: self.expires_at,
+            seq: self.seq,
+            sliding_ttl: self.sliding_ttl,
+            history: self.history.clone(),
+            attempts: self.attempts,
+            updated_at: self.updated_at,
+            touched_at: AtomicU64::new(self.touched_at.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl Value {
+    // Expiry after accounting for reads of a sliding key
+    fn effective_expiry(&self) -> Option<u64> {
+        match self.sliding_ttl {
+            Some(ttl) => {
+                let touched = self.touched_at.load(Ordering::Relaxed);
+                let extended = touched.saturating_add(ttl.saturating_mul(1000));
+                self.expires_at.map(|expires_at| expires_at.max(extended))
+            }
+            None => self.expires_at,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct KvStore {
+    // `None` for an in-memory store that never touches disk
+    #[serde(skip)]
+    file_path: Option<PathBuf>,
+    #[serde(skip)]
+    format: StorageFormat,
+    #[serde(skip)]
+    ttl_mode: TtlMode,
+    #[serde(skip)]
+    next_seq: u64,
+    #[serde(skip)]
+    peak_len: usize,
+    #[serde(skip)]
+    max_ttl: Option<u64>,
+    #[serde(skip)]
+    load_report: LoadReport,
+    #[serde(skip)]
+    sorted_keys: bool,
+    #[serde(skip)]
+    salvage_malformed: bool,
+    #[serde(skip)]
+    bytes_written: AtomicU64,
+    #[serde(skip)]
+    counter_flush_interval: Option<Duration>,
+    #[serde(skip)]
+    last_save: Option<Instant>,
+    #[serde(skip)]
+    coalesce_saves: bool,
+    // In-memory changes not yet written to disk
+    #[serde(skip)]
+    dirty: bool,
     #[serde(skip)]
-    file_path: String,
+    tolerate_save_failures: bool,
+    #[serde(skip)]
+    saves_suspended: bool,
+    #[serde(skip)]
+    max_key_bytes: Option<usize>,
+    #[serde(skip)]
+    autosave_disabled: bool,
+    #[serde(skip)]
+    history_limit: usize,
+    // Per-key overrides of `history_limit`
+    #[serde(skip)]
+    history_limits: HashMap<String, usize>,
+    #[serde(skip)]
+    sync_error: Option<String>,
+    #[serde(skip)]
+    feed: Option<ChangeFeed>,
+    #[serde(skip, default = "system_clock")]
+    clock: Arc<dyn Clock>,
+    // `None` stores values as given
+    #[serde(skip)]
+    codec: Option<Arc<dyn ValueCodec>>,
+    // Keys deleted since the file last matched memory, with when they were
+    // deleted, so `reload_merging` doesn't revive the copies still on disk
+    #[serde(skip)]
+    tombstones: HashMap<String, u64>,
     store: HashMap<String, Value>,
 }
 
+fn system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
 impl KvStore {
     pub fn new() -> Result<Self, Box<dyn Error>> {
-        fs::create_dir_all("storage")?;
-        let store_path = Path::new("storage").join("kv_store.json");
+        Self::with_config(StorageConfig::default())
+    }
+
+    /// Opens the store kept in `config.directory`, creating the directory if
+    /// needed. Stores in different directories are fully independent.
+    pub fn with_config(config: StorageConfig) -> Result<Self, Box<dyn Error>> {
+        if config.cleanup_temp_files && config.directory.exists() {
+            Self::cleanup_temp_files(&config.directory)?;
+        }
+        Self::open(config)
+    }
+
+    /// Removes temp files left in `dir` by saves that were interrupted before
+    /// renaming them into place. Files modified in the last minute are kept,
+    /// since another process may still be writing them. Returns how many
+    /// files were removed.
+    pub fn cleanup_temp_files(dir: &Path) -> Result<usize, Box<dyn Error>> {
+        let mut removed = 0;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if !name.starts_with("kv_store.") || !name.ends_with(".tmp") {
+                continue;
+            }
+            let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+            if age >= STALE_TEMP_FILE_AGE {
+                fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Like `new`, but if the store file cannot be parsed it is renamed to
+    /// `<file name>.corrupt.<timestamp>` for inspection and an empty store
+    /// is opened in its place.
+    pub fn open_or_recover() -> Result<Self, Box<dyn Error>> {
+        Self::open_recovering(StorageConfig::default())
+    }
+
+    fn open_recovering(config: StorageConfig) -> Result<Self, Box<dyn Error>> {
+        let file_name = config.format.file_name();
+        let store_path = config.directory.join(file_name);
+        if let Ok(contents) = fs::read(&store_path) {
+            let now = clock::millis_since_epoch(config.clock.now());
+            if let Err(e) = Self::decode(&contents, now, config.salvage_malformed) {
+                let corrupt_path = config.directory.join(format!("{}.corrupt.{}", file_name, now));
+                fs::rename(&store_path, &corrupt_path)?;
+                eprintln!("Warning: {} could not be loaded ({}); moved it to {}", store_path.display(), e, corrupt_path.display());
+            }
+        }
+        Self::with_config(config)
+    }
+
+    fn open(config: StorageConfig) -> Result<Self, Box<dyn Error>> {
+        fs::create_dir_all(&config.directory)?;
+        let store_path = config.file_path();
         
-        let store = if store_path.exists() {
-            let contents = fs::read_to_string(&store_path)?;
-            serde_json::from_str(&contents).unwrap_or_else(|_| HashMap::new())
+        let (store, malformed) = if store_path.exists() {
+            let contents = fs::read(&store_path)?;
+            Self::decode(&contents, clock::millis_since_epoch(config.clock.now()), config.salvage_malformed)
+                .map_err(|e| format!("failed to load {}: {}", store_path.display(), e))?
         } else {
-            HashMap::new()
+            (HashMap::new(), 0)
         };
+        if malformed > 0 {
+            eprintln!("Warning: skipped {} malformed entries in {}", malformed, store_path.display());
+        }
+        let loaded = store.len();
 
-        let mut kv_store = KvStore {
-            store,
-            file_path: store_path.to_string_lossy().to_string(),
+        let mut kv_store = Self::from_entries(store, Some(store_path));
+        kv_store.format = config.format;
+        kv_store.ttl_mode = config.ttl_mode;
+        kv_store.sorted_keys = config.sorted_keys;
+        kv_store.salvage_malformed = config.salvage_malformed;
+        kv_store.clock = config.clock;
+        kv_store.cleanup_expired()?;
+        kv_store.peak_len = kv_store.len();
+        kv_store.load_report = LoadReport {
+            loaded,
+            expired: loaded - kv_store.store.len(),
+            malformed,
         };
-        
-        kv_store.cleanup_expired();
         Ok(kv_store)
     }
 
-    pub fn get(&self, key: &str) -> Option<String> {
-        self.store.get(key).and_then(|value| {
-            if self.is_expired(value) {
-                None
-            } else {
-                Some(value.data.clone())
-            }
-        })
+    // Loads the store file at `path` into a store that never writes back, so
+    // reading someone else's file leaves it exactly as it was
+    pub(crate) fn open_read_only(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let (store, _) = Self::decode(&contents, clock::millis_since_epoch(SystemClock.now()), false)
+            .map_err(|e| format!("failed to load {}: {}", path.display(), e))?;
+        Ok(Self::from_entries(store, None))
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<(), Box<dyn Error>> {
-        self.set_with_ttl(key, value, None)
+    /// A store that lives only in memory: nothing is ever read from or
+    /// written to disk, and saves succeed without doing anything.
+    pub fn in_memory() -> Self {
+        Self::from_entries(HashMap::new(), None)
     }
 
-    pub fn set_with_ttl(&mut self, key: String, value: String, ttl_seconds: Option<u64>) -> Result<(), Box<dyn Error>> {
-        let expires_at = ttl_seconds.map(|ttl| {
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs()
-                + ttl
-        });
+    fn from_entries(store: HashMap<String, Value>, file_path: Option<PathBuf>) -> Self {
+        let next_seq = store.values().map(|v| v.seq).max().map_or(0, |seq| seq + 1);
+        KvStore {
+            store,
+            file_path,
+            format: StorageFormat::default(),
+            ttl_mode: TtlMode::default(),
+            next_seq,
+            peak_len: 0,
+            max_ttl: None,
+            load_report: LoadReport::default(),
+            sorted_keys: false,
+            salvage_malformed: false,
+            bytes_written: AtomicU64::new(0),
+            counter_flush_interval: None,
+            last_save: None,
+            coalesce_saves: false,
+            dirty: false,
+            tolerate_save_failures: false,
+            saves_suspended: false,
+            max_key_bytes: None,
+            autosave_disabled: false,
+            history_limit: 0,
+            history_limits: HashMap::new(),
+            sync_error: None,
+            feed: None,
+            clock: system_clock(),
+            codec: None,
+            tombstones: HashMap::new(),
+        }
+    }
 
-        self.store.insert(key, Value { 
-            data: value, 
-            expires_at 
-        });
-        self.save()?;
+    /// Exercises persistence end to end in `directory`: writes a TTL'd key
+    /// and an already-expired key, reopens the store from disk, and checks
+    /// that the value and TTL survived and the expired key is gone.
+    pub fn self_test(directory: &Path) -> Result<(), Box<dyn Error>> {
+        let config = StorageConfig { directory: directory.to_path_buf(), ..StorageConfig::default() };
+        let mut store = Self::open(config.clone())?;
+        store.set_with_ttl("selftest:value".to_string(), "ok".to_string(), Some(60))?;
+        store.set_with_ttl("selftest:expired".to_string(), "gone".to_string(), Some(0))?;
+
+        let reopened = Self::open(config)?;
+        if reopened.get("selftest:value").as_deref() != Some("ok") {
+            return Err("value did not survive reopening the store".into());
+        }
+        match reopened.get_ttl("selftest:value") {
+            Some(ttl) if ttl > 0 && ttl <= 60 => {}
+            Some(ttl) => return Err(format!("TTL did not survive reopening (got {}s)", ttl).into()),
+            None => return Err("TTL was lost when reopening the store".into()),
+        }
+        if reopened.store.contains_key("selftest:expired") {
+            return Err("expired key was not removed on load".into());
+        }
         Ok(())
     }
 
-    pub fn delete(&mut self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
-        let result = self.store.remove(key).map(|v| v.data);
-        self.save()?;
-        Ok(result)
+    /// Sets the format used by subsequent saves. Loading always detects the
+    /// format from the file header, so switching formats is transparent.
+    /// The file keeps the name it was opened with; set `StorageConfig::format`
+    /// instead to also get the matching extension.
+    pub fn with_format(mut self, format: StorageFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets how expiry times are persisted by subsequent saves. Both modes
+    /// are recognised on load regardless of this setting. Opening may
+    /// already have saved in the mode from `StorageConfig::ttl_mode`; set
+    /// that instead to control that save too.
+    pub fn with_ttl_mode(mut self, ttl_mode: TtlMode) -> Self {
+        self.ttl_mode = ttl_mode;
+        self
+    }
+
+    /// Caps every TTL set from now on at `seconds`. Sets without a TTL are
+    /// given the ceiling too, so no key written through this store can be
+    /// permanent. Existing entries are left as loaded.
+    pub fn with_max_ttl(mut self, seconds: u64) -> Self {
+        self.max_ttl = Some(seconds);
+        self
+    }
+
+    /// Reads the time from `clock` instead of the system clock from now on.
+    /// Expired entries were already dropped at open using the clock in
+    /// `StorageConfig`; set that too to control loading.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Encodes values written from now on with `codec`, and decodes values
+    /// through it when they are read. Entries already in the store, including
+    /// any loaded from its file, must have been written with the same codec.
+    pub fn with_codec(mut self, codec: Arc<dyn ValueCodec>) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+
+    /// Writes entries in key order and leaves out each entry's write order
+    /// and `updated_at` (both saved as 0), so the same entries, values and
+    /// expiries always produce the same bytes, however they were written.
+    /// After reloading such a file, `recent_keys` and `modified_between`
+    /// only know about writes made since. Set `StorageConfig::sorted_keys`
+    /// instead to also sort the save that drops expired entries on open.
+    pub fn with_sorted_keys(mut self, sorted: bool) -> Self {
+        self.sorted_keys = sorted;
+        self
+    }
+
+    /// Total bytes written to disk by saves since the store was opened.
+    /// Every save rewrites the whole snapshot, so this grows with both the
+    /// number of writes and the size of the store.
+    pub fn write_bytes_total(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Coalesces `increment` saves: counters change in memory immediately,
+    /// but are written to disk at most once per `interval` (checked on each
+    /// increment) or when `flush` is called. Other writes still save at once.
+    /// Share the store with `SharedKvStore::start_flusher` to also write
+    /// the last increments of a burst without waiting for another one.
+    pub fn with_counter_buffering(mut self, interval: Duration) -> Self {
+        self.counter_flush_interval = Some(interval);
+        self
+    }
+
+    /// Rejects writes that would store a key longer than `max` bytes with a
+    /// `KeyTooLong` error, leaving the store unchanged. Keys already in the
+    /// file are loaded regardless. Unlimited by default.
+    pub fn with_max_key_bytes(mut self, max: usize) -> Self {
+        self.max_key_bytes = Some(max);
+        self
+    }
+
+    /// Skips a save that comes within a millisecond of the previous write, so
+    /// a burst of writes costs one disk write instead of one each. Skipped
+    /// changes are written by the next save after that, by `flush`, or when
+    /// the store is dropped; until then they are only in memory and
+    /// `sync_status` reports them as pending. Nothing writes them when the
+    /// window ends on its own, so share the store and run
+    /// `SharedKvStore::start_flusher` to bound how long the last write of a
+    /// burst waits. Off by default.
+    pub fn with_save_coalescing(mut self, enabled: bool) -> Self {
+        self.coalesce_saves = enabled;
+        self
+    }
+
+    /// Keeps up to `limit` earlier values of each key when it is overwritten
+    /// by a set, readable through `history`. Off (0) by default.
+    pub fn with_history(mut self, limit: usize) -> Self {
+        self.history_limit = limit;
+        self
+    }
+
+    /// With autosave off, writes only change the in-memory store and nothing
+    /// is written to disk until `flush` is called or the store is dropped.
+    /// Autosave is on by default.
+    pub fn with_autosave(mut self, enabled: bool) -> Self {
+        self.autosave_disabled = !enabled;
+        self
+    }
+
+    /// Keeps writes in memory when saving fails (e.g. the disk is full)
+    /// instead of returning the error. The store is then reported as
+    /// unsynced by `sync_status` until a later save or `flush` succeeds.
+    pub fn with_unsynced_writes(mut self, tolerate: bool) -> Self {
+        self.tolerate_save_failures = tolerate;
+        self
     }
 
-    fn save(&self) -> Result<(), Box<dyn Error>> {
-        let store_path = Path::new("storage").join("kv_store.json");
-        if let Ok(serialized) = serde_json::to_string(&self.store) {
-            fs::write(store_path, serialized)?;
+    /// Whether every change has reached the file. A failed save takes
+    /// precedence over changes that are merely waiting to be written.
+    pub fn sync_status(&self) -> SyncStatus {
+        match &self.sync_error {
+            Some(error) => SyncStatus::Unsynced(error.clone()),
+            None if self.dirty => SyncStatus::Pending,
+            None => SyncStatus::Synced,
         }
-        Ok(())
     }
 
-    pub fn list(&self) -> Vec<(String, String)> {
-        self.store
+    pub fn load_report(&self) -> LoadReport {
+        self.load_report
+    }
+
+    /// Checks the store file at `path` without opening a store, so nothing is
+    /// expired, rewritten, or dropped. Only failing to read the file is an
+    /// error; problems with its contents are described by the report.
+    pub fn verify_file<P: AsRef<Path>>(path: P) -> Result<VerifyReport, Box<dyn Error>> {
+        let contents = fs::read(path)?;
+        if contents.starts_with(BINARY_MAGIC) {
+            // Bincode has no per-entry structure to inspect; it decodes entirely or not at all
+            return Ok(match Self::decode(&contents, clock::millis_since_epoch(SystemClock.now()), false) {
+                Ok((entries, _)) => VerifyReport { well_formed: true, entries: entries.len(), malformed: Vec::new() },
+                Err(_) => VerifyReport::default(),
+            });
+        }
+
+        let raw = match serde_json::from_slice::<RelativeSnapshot<HashMap<String, serde_json::Value>>>(&contents) {
+            Ok(snapshot) => snapshot.entries,
+            Err(_) => match serde_json::from_slice(&contents) {
+                Ok(entries) => entries,
+                Err(_) => return Ok(VerifyReport::default()),
+            },
+        };
+        let mut malformed: Vec<String> = raw
             .iter()
-            .filter(|(_, value)| !self.is_expired(value))
-            .map(|(k, v)| (k.clone(), v.data.clone()))
-            .collect()
+            .filter(|(_, value)| Self::entry_from_json((*value).clone()).is_err())
+            .map(|(key, _)| key.clone())
+            .collect();
+        malformed.sort();
+        Ok(VerifyReport { well_formed: true, entries: raw.len(), malformed })
     }
 
-    fn is_expired(&self, value: &Value) -> bool {
-        if let Some(expires_at) = value.expires_at {
-            let now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            expires_at <= now
+    // Returns the entries plus a count of malformed entries that were skipped
+    // with `salvage` set; without it the first one is an error. Relative
+    // expiries are resolved against `now`
+    fn decode(contents: &[u8], now: u64, salvage: bool) -> Result<(HashMap<String, Value>, usize), Box<dyn Error>> {
+        let mut malformed = 0;
+        let snapshot = match contents.strip_prefix(BINARY_MAGIC) {
+            Some(rest) => match rest.split_first() {
+                Some((&BINARY_VERSION, payload)) => bincode::deserialize(payload)?,
+                Some((version, _)) => return Err(format!("unsupported snapshot version {}", version).into()),
+                None => return Err("truncated snapshot header".into()),
+            },
+            // Anything without the magic is a JSON snapshot
+            None => {
+                let (raw, relative) = match serde_json::from_slice::<RelativeSnapshot<HashMap<String, serde_json::Value>>>(contents) {
+                    Ok(RelativeSnapshot { entries, .. }) => (entries, true),
+                    Err(_) => (serde_json::from_slice(contents)?, false),
+                };
+                let mut entries = HashMap::with_capacity(raw.len());
+                for (key, raw_value) in raw {
+                    match Self::entry_from_json(raw_value) {
+                        Ok(value) => {
+                            entries.insert(key, value);
+                        }
+                        Err(_) if salvage => malformed += 1,
+                        Err(e) => return Err(format!("malformed entry '{}': {}", key, e).into()),
+                    }
+                }
+                if relative {
+                    DiskSnapshot::Relative(entries)
+                } else {
+                    DiskSnapshot::Absolute(entries)
+                }
+            }
+        };
+
+        let entries = match snapshot {
+            DiskSnapshot::Absolute(entries) => entries,
+            DiskSnapshot::Relative(entries) => {
+                entries
+                    .into_iter()
+                    .map(|(key, mut value)| {
+                        value.expires_at = value.expires_at.map(|remaining| now.saturating_add(remaining));
+                        (key, value)
+                    })
+                    .collect()
+            }
+        };
+        Ok((entries, malformed))
+    }
+
+    // Parses one JSON entry, converting an expiry in seconds from files
+    // written before millisecond TTLs
+    fn entry_from_json(mut raw: serde_json::Value) -> serde_json::Result<Value> {
+        if let Some(entry) = raw.as_object_mut() {
+            if let Some(seconds) = entry.remove("expires_at") {
+                let millis = match seconds.as_u64() {
+                    Some(seconds) => seconds.saturating_mul(1000).into(),
+                    None => seconds,
+                };
+                entry.insert("expires_at_ms".to_string(), millis);
+            }
+        }
+        serde_json::from_value(raw)
+    }
+
+    fn write_snapshot<W: Write>(&self, w: W) -> Result<(), Box<dyn Error>> {
+        let now = self.now_ms();
+        let entries = self.store.iter().map(|(key, value)| {
+            let mut expires_at = value.effective_expiry();
+            if self.ttl_mode == TtlMode::Relative {
+                expires_at = expires_at.map(|expires_at| expires_at.saturating_sub(now));
+            }
+            // Write order and timestamps would make sorted output depend on
+            // how the entries were written, so sorted saves drop them
+            let (seq, updated_at) = if self.sorted_keys { (0, 0) } else { (value.seq, value.updated_at) };
+            let saved = SavedValue {
+                data: &value.data,
+                expires_at,
+                seq,
+                sliding_ttl: value.sliding_ttl,
+                history: &value.history,
+                attempts: value.attempts,
+                updated_at,
+            };
+            (key.as_str(), saved)
+        });
+
+        if self.sorted_keys {
+            self.write_entries(w, entries.collect::<BTreeMap<_, _>>())
         } else {
-            false
+            self.write_entries(w, entries.collect::<HashMap<_, _>>())
         }
     }
 
-    fn cleanup_expired(&mut self) {
-        let expired_keys: Vec<String> = self.store
-            .iter()
-            .filter(|(_, value)| self.is_expired(value))
-            .map(|(key, _)| key.clone())
+    fn write_entries<W: Write, M: Serialize>(&self, mut w: W, entries: M) -> Result<(), Box<dyn Error>> {
+        let snapshot = match self.ttl_mode {
+            TtlMode::Absolute => DiskSnapshot::Absolute(entries),
+            TtlMode::Relative => DiskSnapshot::Relative(entries),
+        };
+
+        match self.format {
+            StorageFormat::Json => match snapshot {
+                DiskSnapshot::Absolute(entries) => serde_json::to_writer(w, &entries)?,
+                DiskSnapshot::Relative(entries) => {
+                    serde_json::to_writer(w, &RelativeSnapshot { ttl_mode: RelativeTag::Relative, entries })?
+                }
+            },
+            StorageFormat::Bincode => {
+                w.write_all(BINARY_MAGIC)?;
+                w.write_all(&[BINARY_VERSION])?;
+                bincode::serialize_into(w, &snapshot)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the live entries to `path` as a flat JSON object of key to
+    /// value, without TTLs. Returns how many entries were written.
+    pub fn export_json(&self, path: &Path) -> Result<usize, Box<dyn Error>> {
+        let entries: BTreeMap<String, String> = self.list().into_iter().collect();
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, &entries)?;
+        writer.flush()?;
+        Ok(entries.len())
+    }
+
+    /// Sets every entry of a flat JSON object in `path`, overwriting existing
+    /// keys and saving once. String values are stored as-is; other JSON
+    /// values are stored as JSON text. Returns how many entries were set.
+    pub fn import_json(&mut self, path: &Path) -> Result<usize, Box<dyn Error>> {
+        let object: serde_json::Map<String, serde_json::Value> = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        let entries: Vec<(String, String, Option<u64>)> = object
+            .into_iter()
+            .map(|(key, value)| match value {
+                serde_json::Value::String(text) => (key, text, None),
+                other => (key, other.to_string(), None),
+            })
             .collect();
+        let count = entries.len();
+        self.set_many(entries)?;
+        Ok(count)
+    }
+
+    /// Writes the live entries to `path` as CSV with `key,value` columns.
+    /// Returns how many entries were written.
+    pub fn export_csv(&self, path: &Path) -> Result<usize, Box<dyn Error>> {
+        self.export_csv_projected(&[Column::Key, Column::Value], File::create(path)?)
+    }
 
-        for key in expired_keys {
-            self.store.remove(&key);
+    /// Sets every row of a `key,value` CSV file in `path`, as written by
+    /// `export_csv`, overwriting existing keys and saving once. A leading
+    /// `key,value` header row is skipped. Returns how many entries were set.
+    pub fn import_csv(&mut self, path: &Path) -> Result<usize, Box<dyn Error>> {
+        let mut records = parse_csv(&fs::read_to_string(path)?)?;
+        if records.first().is_some_and(|header| header == &["key", "value"]) {
+            records.remove(0);
         }
-        
-        if let Err(e) = self.save() {
-            eprintln!("Failed to save during cleanup: {}", e);
+        let mut entries = Vec::with_capacity(records.len());
+        for (i, record) in records.into_iter().enumerate() {
+            let [key, value]: [String; 2] = record
+                .try_into()
+                .map_err(|_| format!("CSV record {} does not have exactly two fields", i + 1))?;
+            entries.push((key, value, None));
         }
+        let count = entries.len();
+        self.set_many(entries)?;
+        Ok(count)
     }
 
-    // Helper method to get TTL information
-    pub fn get_ttl(&self, key: &str) -> Option<u64> {
-        self.store.get(key).and_then(|value| {
-            if self.is_expired(value) {
-                None
+    /// Writes live entries as CSV, one row per key in key order, with a
+    /// header row and one column per entry in `columns`. Returns how many
+    /// rows were written, not counting the header.
+    pub fn export_csv_projected<W: Write>(&self, columns: &[Column], w: W) -> Result<usize, Box<dyn Error>> {
+        fn field(text: &str) -> String {
+            if text.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", text.replace('"', "\"\""))
             } else {
-                value.expires_at.map(|expires_at| {
-                    let now = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs();
-                    expires_at.saturating_sub(now)
-                })
+                text.to_string()
             }
-        })
+        }
+        fn write_row<W: Write>(w: &mut W, fields: Vec<String>) -> io::Result<()> {
+            let line: Vec<String> = fields.iter().map(|f| field(f)).collect();
+            writeln!(w, "{}", line.join(","))
+        }
+
+        let mut w = BufWriter::new(w);
+        let header = columns
+            .iter()
+            .map(|column| match column {
+                Column::Key => "key".to_string(),
+                Column::Value => "value".to_string(),
+                Column::Ttl => "ttl".to_string(),
+                Column::JsonPath(path) => path.clone(),
+            })
+            .collect();
+        write_row(&mut w, header)?;
+
+        let mut entries = self.list();
+        entries.sort();
+        for (key, data) in &entries {
+            let json = Self::parse_json(data);
+            let row = columns
+                .iter()
+                .map(|column| match column {
+                    Column::Key => key.clone(),
+                    Column::Value => data.clone(),
+                    Column::Ttl => self.get_ttl(key).map(|ttl| ttl.to_string()).unwrap_or_default(),
+                    Column::JsonPath(path) => {
+                        let pointer: String = path
+                            .split('.')
+                            .map(|segment| format!("/{}", segment.replace('~', "~0").replace('/', "~1")))
+                            .collect();
+                        match json.pointer(&pointer) {
+                            Some(serde_json::Value::String(text)) => text.clone(),
+                            Some(value) => value.to_string(),
+                            None => String::new(),
+                        }
+                    }
+                })
+                .collect();
+            write_row(&mut w, row)?;
+        }
+        w.flush()?;
+        Ok(entries.len())
     }
 
-    pub fn get_all(&self) -> Vec<(String, Value)> {
-        self.store
+    /// Writes a JSON object mapping each live key in `keys` to its value.
+    /// Missing and expired keys are skipped. Returns how many were written.
+    pub fn export_subset<W: Write>(&self, keys: &[&str], w: W) -> Result<usize, Box<dyn Error>> {
+        let subset: BTreeMap<&str, String> = keys
             .iter()
-            .filter(|(_, value)| !self.is_expired(value))
-            .map(|(k, v)| (k.clone(), v.clone()))
-            .collect()
+            .filter_map(|key| self.get(key).map(|value| (*key, value)))
+            .collect();
+        serde_json::to_writer(w, &subset)?;
+        Ok(subset.len())
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.store.is_empty()
+    /// Size in bytes a save would write right now, computed without
+    /// buffering the snapshot or touching the disk.
+    pub fn serialized_size(&self) -> Result<usize, Box<dyn Error>> {
+        let mut counter = CountingWriter::new(io::sink());
+        self.write_snapshot(&mut counter)?;
+        Ok(counter.written)
     }
 
-    pub fn len(&self) -> usize {
-        self.store.len()
+    // Milliseconds since the Unix epoch by the store's clock, clamped to 0
+    // if it reads before 1970 rather than panicking
+    fn now_ms(&self) -> u64 {
+        clock::millis_since_epoch(self.clock.now())
     }
-} 
\ No newline at end of file
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.store.get(key).and_then(|value| {
+            if self.is_expired(value) {
+                None
+            } else {
+                if value.sliding_ttl.is_some() {
+                    value.touched_at.fetch_max(self.now_ms(), Ordering::Relaxed);
+                }
+                Some(self.decode_data(&value.data).into_owned())
+            }
+        })
+    }
+
+    /// Whether `key` is present and not expired, without copying its value.
+    /// Unlike `get`, this doesn't count as a read for sliding expiry.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.live(key).is_some()
+    }
+
+    /// The value of `key` with its TTL, type, and write order, from a single
+    /// lookup. Counts as a read for sliding expiry, like `get`.
+    pub fn get_entry(&self, key: &str) -> Option<EntryInfo> {
+        let value = self.live(key)?;
+        if value.sliding_ttl.is_some() {
+            value.touched_at.fetch_max(self.now_ms(), Ordering::Relaxed);
+        }
+        let data = self.decode_data(&value.data).into_owned();
+        Some(EntryInfo {
+            ttl: value.effective_expiry().map(|expires_at| expires_at.saturating_sub(self.now_ms()).div_ceil(1000)),
+            value_type: ValueType::of(&Self::parse_json(&data)),
+            value: data,
+            seq: value.seq,
+        })
+    }
+
+    /// Value of the first live key in `keys`, along with the key that matched.
+    pub fn get_first(&self, keys: &[&str]) -> Option<(String, String)> {
+        keys.iter().find_map(|key| self.get(key).map(|value| (key.to_string(), value)))
+    }
+
+    /// Values of `keys` as a JSON object keyed by key, with `null` for
+    /// missing keys. Values are included as JSON when they parse as JSON,
+    /// and as strings otherwise.
+    pub fn mget_object(&self, keys: &[&str]) -> serde_json::Value {
+        let object: serde_json::Map<String, serde_json::Value> = keys
+            .iter()
+            .map(|key| {
+                let value = self.get(key).map_or(serde_json::Value::Null, |data| Self::parse_json(&data));
+                (key.to_string(), value)
+            })
+            .collect();
+        serde_json::Value::Object(object)
+    }
+
+    /// Values of `keys`, in the same order, with `None` for missing keys.
+    pub fn get_many(&self, keys: &[&str]) -> Vec<Option<String>> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Stores `body` as the macro `name`, without a TTL. Macros live under
+    /// `MACRO_PREFIX`, where ordinary writes are refused, and are left out of
+    /// listings, counts, exports and the change feed; `clear` keeps them.
+    pub fn define_macro(&mut self, name: &str, body: String) -> Result<(), Box<dyn Error>> {
+        let key = format!("{}{}", MACRO_PREFIX, name);
+        self.check_key_len(&key)?;
+        self.insert_value(key, body, None);
+        self.save()
+    }
+
+    /// Body of the macro `name`, if one is defined.
+    pub fn macro_body(&self, name: &str) -> Option<String> {
+        self.get(&format!("{}{}", MACRO_PREFIX, name))
+    }
+
+    /// Sets every `(key, value, ttl_seconds)` entry, then saves once instead
+    /// of once per key.
+    pub fn set_many(&mut self, entries: Vec<(String, String, Option<u64>)>) -> Result<(), Box<dyn Error>> {
+        for (key, _, _) in &entries {
+            self.check_key(key)?;
+        }
+        for (key, value, ttl_seconds) in entries {
+            let expires_at = self.expiry_for(ttl_seconds);
+            self.insert_value(key, value, expires_at);
+        }
+        self.save()
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<(), Box<dyn Error>> {
+        self.set_with_ttl(key, value, None)
+    }
+
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl_seconds: Option<u64>) -> Result<(), Box<dyn Error>> {
+        self.set_with_ttl_ms(key, value, ttl_seconds.map(|seconds| seconds.saturating_mul(1000)))
+    }
+
+    /// Like `set_with_ttl`, with the TTL in milliseconds.
+    pub fn set_with_ttl_ms(&mut self, key: String, value: String, ttl_ms: Option<u64>) -> Result<(), Box<dyn Error>> {
+        self.check_key(&key)?;
+        let expires_at = self.expiry_for_ms(ttl_ms);
+
+        self.insert_value(key, value, expires_at);
+        self.save()?;
+        Ok(())
+    }
+
+    /// Sets `key` to `value` but keeps the expiry of a live key, including a
+    /// sliding window and how recently it was read. Other keys are set as
+    /// `set` would.
+    pub fn set_keep_ttl(&mut self, key: String, value: String) -> Result<(), Box<dyn Error>> {
+        self.check_key(&key)?;
+        self.rewrite_value(&key, value);
+        // A new value starts a fresh run of attempts, as it does for `set`
+        if let Some(entry) = self.store.get_mut(&key) {
+            entry.attempts = 0;
+        }
+        self.save()
+    }
+
+    /// Sets `key` to `new`, as `set` would, only if its current value is
+    /// `expected`; `None` means the key must be absent or expired. Returns
+    /// whether the swap happened. Nothing is saved when it doesn't.
+    pub fn compare_and_swap(&mut self, key: &str, expected: Option<&str>, new: String) -> Result<bool, Box<dyn Error>> {
+        let current = self.live(key).map(|value| self.decode_data(&value.data));
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+        self.set(key.to_string(), new)?;
+        Ok(true)
+    }
+
+    /// Sets `key`, as `set` would, only if `timestamp` (milliseconds since
+    /// the Unix epoch) is later than the stored entry's `updated_at`; absent
+    /// and expired keys are always written. The entry's `updated_at` becomes
+    /// `timestamp`, for last-write-wins syncing. Returns whether it wrote.
+    pub fn set_if_newer(&mut self, key: &str, value: String, timestamp: u64) -> Result<bool, Box<dyn Error>> {
+        self.check_key(key)?;
+        if self.live(key).is_some_and(|current| current.updated_at >= timestamp) {
+            return Ok(false);
+        }
+        let expires_at = self.expiry_for(None);
+        self.insert_value(key.to_string(), value, expires_at);
+        if let Some(entry) = self.store.get_mut(key) {
+            entry.updated_at = timestamp;
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Stores `f` applied to the current values of `from` under `key`, for
+    /// keeping denormalized data in step with its sources. Absent or expired
+    /// sources are passed as `None`. Re-run it after a source changes.
+    pub fn set_derived(&mut self, key: &str, from: &[&str], f: impl Fn(&[Option<String>]) -> String) -> Result<(), Box<dyn Error>> {
+        let sources: Vec<Option<String>> = from.iter().map(|source| self.get(source)).collect();
+        self.set(key.to_string(), f(&sources))
+    }
+
+    fn check_key(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        if is_reserved(key) {
+            return Err(ReservedKey { key: key.to_string() }.into());
+        }
+        Ok(self.check_key_len(key)?)
+    }
+
+    fn check_key_len(&self, key: &str) -> Result<(), KeyTooLong> {
+        match self.max_key_bytes {
+            Some(max) if key.len() > max => Err(KeyTooLong { len: key.len(), max }),
+            _ => Ok(()),
+        }
+    }
+
+    // Absolute expiry for a requested TTL, after applying any TTL ceiling
+    fn expiry_for(&self, ttl_seconds: Option<u64>) -> Option<u64> {
+        self.expiry_for_ms(ttl_seconds.map(|seconds| seconds.saturating_mul(1000)))
+    }
+
+    fn expiry_for_ms(&self, ttl_ms: Option<u64>) -> Option<u64> {
+        let max_ms = self.max_ttl.map(|max| max.saturating_mul(1000));
+        let ttl = match (ttl_ms, max_ms) {
+            (Some(ttl), Some(max)) => Some(ttl.min(max)),
+            (ttl, max) => ttl.or(max),
+        };
+        ttl.map(|ttl| self.now_ms().saturating_add(ttl))
+    }
+
+    /// Sets a key whose expiry follows `policy`. Any TTL ceiling from
+    /// `with_max_ttl` applies to the initial expiry and the sliding window.
+    pub fn set_with_policy(&mut self, key: String, value: String, policy: ExpiryPolicy) -> Result<(), Box<dyn Error>> {
+        self.check_key(&key)?;
+        let (ttl, sliding_ttl) = match policy {
+            ExpiryPolicy::Fixed(ttl) => (Some(ttl), None),
+            ExpiryPolicy::Sliding(ttl) => (Some(ttl), Some(self.max_ttl.map_or(ttl, |max| ttl.min(max)))),
+            ExpiryPolicy::Never => (None, None),
+        };
+        let expires_at = self.expiry_for(ttl);
+
+        self.insert_value(key.clone(), value, expires_at);
+        if let Some(entry) = self.store.get_mut(&key) {
+            entry.sliding_ttl = sliding_ttl;
+        }
+        self.save()?;
+        Ok(())
+    }
+
+    fn encode_data(&self, data: String) -> String {
+        match &self.codec {
+            Some(codec) => codec.encode(&data),
+            None => data,
+        }
+    }
+
+    // Stored data as the caller wrote it
+    fn decode_data<'a>(&self, data: &'a str) -> Cow<'a, str> {
+        match &self.codec {
+            Some(codec) => Cow::Owned(codec.decode(data)),
+            None => Cow::Borrowed(data),
+        }
+    }
+
+    fn insert_value(&mut self, key: String, data: String, expires_at: Option<u64>) {
+        let data = self.encode_data(data);
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let limit = self.history_limits.get(&key).copied().unwrap_or(self.history_limit);
+        let mut history = Vec::new();
+        if limit > 0 {
+            if let Some(old) = self.store.remove(&key).filter(|old| !self.is_expired(old)) {
+                history = old.history;
+                history.push(old.data);
+                history.drain(..history.len().saturating_sub(limit));
+            }
+        }
+        self.store.insert(key, Value {
+            data,
+            expires_at,
+            seq,
+            sliding_ttl: None,
+            history,
+            attempts: 0,
+            updated_at: self.now_ms(),
+            touched_at: AtomicU64::new(0),
+        });
+        self.peak_len = self.peak_len.max(self.store.len());
+    }
+
+    // Replaces the data at `key` like `insert_value`, carrying over a live
+    // entry's expiry, sliding window, last read time and failed attempts
+    fn rewrite_value(&mut self, key: &str, data: String) {
+        let kept = self.live(key).map(|value| (value.expires_at, value.sliding_ttl, value.touched_at.load(Ordering::Relaxed), value.attempts));
+        let (expires_at, sliding_ttl, touched_at, attempts) = kept.unwrap_or_else(|| (self.expiry_for(None), None, 0, 0));
+        self.insert_value(key.to_string(), data, expires_at);
+        if let Some(entry) = self.store.get_mut(key) {
+            entry.sliding_ttl = sliding_ttl;
+            entry.touched_at = AtomicU64::new(touched_at);
+            entry.attempts = attempts;
+        }
+    }
+
+    /// Returns the id stored at `key`, first storing a fresh random UUID if
+    /// the key is absent or expired. Taking `&mut self` means callers sharing
+    /// a store always agree on the id.
+    pub fn get_or_assign_id(&mut self, key: &str) -> Result<String, Box<dyn Error>> {
+        self.get_or_assign_id_with(key, || uuid::Uuid::new_v4().to_string())
+    }
+
+    /// Like `get_or_assign_id`, but generates new ids with `generate`, e.g.
+    /// to make ids deterministic.
+    pub fn get_or_assign_id_with(&mut self, key: &str, generate: impl FnOnce() -> String) -> Result<String, Box<dyn Error>> {
+        if let Some(id) = self.get(key) {
+            return Ok(id);
+        }
+        let id = generate();
+        self.set(key.to_string(), id.clone())?;
+        Ok(id)
+    }
+
+    /// Sets each default whose key is absent (or expired), leaving existing
+    /// keys untouched. Returns how many defaults were applied.
+    pub fn seed_defaults(&mut self, defaults: &[(&str, &str)]) -> Result<usize, Box<dyn Error>> {
+        for (key, _) in defaults {
+            self.check_key(key)?;
+        }
+        let mut seeded = 0;
+        for (key, value) in defaults {
+            if self.live(key).is_none() {
+                let expires_at = self.expiry_for(None);
+                self.insert_value(key.to_string(), value.to_string(), expires_at);
+                seeded += 1;
+            }
+        }
+        if seeded > 0 {
+            self.save()?;
+        }
+        Ok(seeded)
+    }
+
+    /// Loads newline-delimited `key\tvalue` records straight into the map,
+    /// a line at a time, and saves once. Lines without a tab, with an empty
+    /// key, or with a key over the `with_max_key_bytes` limit are skipped.
+    /// Returns `(loaded, skipped)` rather than a bare count, so skipped
+    /// lines can be reported. A reader gives no record count, so use
+    /// `bulk_load_with_capacity` to reserve room up front when it is known.
+    pub fn bulk_load<R: Read>(&mut self, r: R) -> Result<(usize, usize), Box<dyn Error>> {
+        self.bulk_load_with_capacity(r, 0)
+    }
+
+    /// Like `bulk_load`, after reserving room in the map for `records` more
+    /// entries.
+    pub fn bulk_load_with_capacity<R: Read>(&mut self, r: R, records: usize) -> Result<(usize, usize), Box<dyn Error>> {
+        self.store.reserve(records);
+        let mut reader = BufReader::new(r);
+        let mut line = String::new();
+        let (mut loaded, mut skipped) = (0, 0);
+
+        let expires_at = self.expiry_for(None);
+        while reader.read_line(&mut line)? > 0 {
+            let record = line.trim_end_matches(['\n', '\r']);
+            match record.split_once('\t') {
+                Some((key, value)) if !key.is_empty() && self.check_key(key).is_ok() => {
+                    self.insert_value(key.to_string(), value.to_string(), expires_at);
+                    loaded += 1;
+                }
+                _ if record.is_empty() => {}
+                _ => skipped += 1,
+            }
+            line.clear();
+        }
+
+        if loaded > 0 {
+            self.save()?;
+        }
+        Ok((loaded, skipped))
+    }
+
+    /// Loads environment variables named `prefix` then `_` then a non-empty
+    /// rest, keyed by that rest lowercased, so `APP_DB_HOST` with prefix
+    /// `APP_` (or `APP`) becomes `db_host` while `APPLE_PIE` is ignored.
+    /// Variables whose name or value isn't valid UTF-8 are skipped. Fails
+    /// without loading anything if a key would be over the
+    /// `with_max_key_bytes` limit. Returns how many variables were loaded.
+    pub fn load_env(&mut self, prefix: &str) -> Result<usize, Box<dyn Error>> {
+        let prefix = prefix.strip_suffix('_').unwrap_or(prefix);
+        let mut entries = Vec::new();
+        for (name, value) in std::env::vars_os() {
+            let (Some(name), Some(value)) = (name.to_str(), value.to_str()) else {
+                continue;
+            };
+            let Some(rest) = name.strip_prefix(prefix).and_then(|rest| rest.strip_prefix('_')) else {
+                continue;
+            };
+            if rest.is_empty() {
+                continue;
+            }
+            let key = rest.to_lowercase();
+            self.check_key(&key)?;
+            entries.push((key, value.to_string()));
+        }
+
+        let expires_at = self.expiry_for(None);
+        let loaded = entries.len();
+        for (key, value) in entries {
+            self.insert_value(key, value, expires_at);
+        }
+        if loaded > 0 {
+            self.save()?;
+        }
+        Ok(loaded)
+    }
+
+    /// Writes live entries as `.env` lines, `PREFIX_KEY=value`, in key order:
+    /// the reverse of `load_env`. Keys are uppercased with characters not
+    /// allowed in variable names replaced by `_`, so distinct keys can map to
+    /// the same name. Values with anything beyond letters, digits and
+    /// `_-.,:/@+` are double-quoted with `"`, `\`, `$` and newlines escaped.
+    /// Returns how many lines were written.
+    pub fn export_env<W: Write>(&self, prefix: &str, w: W) -> Result<usize, Box<dyn Error>> {
+        fn quoted(value: &str) -> String {
+            if value.chars().all(|c| c.is_ascii_alphanumeric() || "_-.,:/@+".contains(c)) {
+                return value.to_string();
+            }
+            let mut quoted = String::with_capacity(value.len() + 2);
+            quoted.push('"');
+            for c in value.chars() {
+                match c {
+                    '"' | '\\' | '$' => {
+                        quoted.push('\\');
+                        quoted.push(c);
+                    }
+                    '\n' => quoted.push_str("\\n"),
+                    '\r' => quoted.push_str("\\r"),
+                    c => quoted.push(c),
+                }
+            }
+            quoted.push('"');
+            quoted
+        }
+
+        let separator = if prefix.is_empty() || prefix.ends_with('_') { "" } else { "_" };
+        let mut entries = self.list();
+        entries.sort();
+        let mut w = BufWriter::new(w);
+        for (key, value) in &entries {
+            let name: String = key
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+                .collect();
+            writeln!(w, "{}{}{}={}", prefix, separator, name, quoted(value))?;
+        }
+        w.flush()?;
+        Ok(entries.len())
+    }
+
+    /// Overrides the history limit from `with_history` for one key, e.g. to
+    /// keep more versions of a hot key or none at all. History already over
+    /// the new limit is trimmed now and saved with the next write or `flush`.
+    pub fn set_history_limit(&mut self, key: &str, limit: usize) {
+        self.history_limits.insert(key.to_string(), limit);
+        if let Some(value) = self.store.get_mut(key) {
+            let excess = value.history.len().saturating_sub(limit);
+            if excess > 0 {
+                value.history.drain(..excess);
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Earlier values of a live `key`, oldest first. Empty unless the store
+    /// keeps history.
+    pub fn history(&self, key: &str) -> Vec<String> {
+        let Some(value) = self.live(key) else {
+            return Vec::new();
+        };
+        value.history.iter().map(|old| self.decode_data(old).into_owned()).collect()
+    }
+
+    /// Trims every key's history to its newest `keep` versions and returns
+    /// how many versions were dropped.
+    pub fn prune_history(&mut self, keep: usize) -> Result<usize, Box<dyn Error>> {
+        let mut dropped = 0;
+        for value in self.store.values_mut() {
+            let excess = value.history.len().saturating_sub(keep);
+            value.history.drain(..excess);
+            dropped += excess;
+        }
+
+        if dropped > 0 {
+            self.save()?;
+        }
+        Ok(dropped)
+    }
+
+    /// Cuts the value at `key` down to its first `max_chars` characters,
+    /// keeping its TTL. Returns whether the value changed; missing keys and
+    /// values already short enough are left alone.
+    pub fn truncate_value(&mut self, key: &str, max_chars: usize) -> Result<bool, Box<dyn Error>> {
+        self.truncate_value_keeping(key, max_chars, KeepEnd::Head)
+    }
+
+    /// Like `truncate_value`, but `keep` chooses whether the first or the
+    /// last `max_chars` characters survive, e.g. `Tail` for log-like values.
+    pub fn truncate_value_keeping(&mut self, key: &str, max_chars: usize, keep: KeepEnd) -> Result<bool, Box<dyn Error>> {
+        let Some(value) = self.live(key) else {
+            return Ok(false);
+        };
+        let current = self.decode_data(&value.data);
+        let len = current.chars().count();
+        if len <= max_chars {
+            return Ok(false);
+        }
+
+        let data = match keep {
+            KeepEnd::Head => current.chars().take(max_chars).collect(),
+            KeepEnd::Tail => current.chars().skip(len - max_chars).collect(),
+        };
+        self.rewrite_value(key, data);
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Applies `patch` to the JSON value at `key` with RFC 7386 merge-patch
+    /// semantics: object members are merged recursively, `null` members are
+    /// removed, and anything else replaces the target. A missing key starts
+    /// as an empty object; the existing TTL is kept.
+    pub fn merge_patch(&mut self, key: &str, patch: serde_json::Value) -> Result<(), Box<dyn Error>> {
+        fn apply(target: &mut serde_json::Value, patch: serde_json::Value) {
+            let serde_json::Value::Object(patch) = patch else {
+                *target = patch;
+                return;
+            };
+            if !target.is_object() {
+                *target = serde_json::Value::Object(serde_json::Map::new());
+            }
+            if let serde_json::Value::Object(target) = target {
+                for (name, value) in patch {
+                    if value.is_null() {
+                        target.remove(&name);
+                    } else {
+                        apply(target.entry(name).or_insert(serde_json::Value::Null), value);
+                    }
+                }
+            }
+        }
+
+        self.check_key(key)?;
+        let mut target = match self.live(key) {
+            Some(value) => Self::parse_json(&self.decode_data(&value.data)),
+            None => serde_json::Value::Object(serde_json::Map::new()),
+        };
+        apply(&mut target, patch);
+
+        let data = serde_json::to_string(&target)?;
+        self.rewrite_value(key, data);
+        self.save()?;
+        Ok(())
+    }
+
+    /// Pushes `element` onto the JSON array stored at `key`, dropping the
+    /// oldest elements so at most `max_len` remain. A missing key starts as
+    /// an empty array; the existing TTL is kept.
+    pub fn append_capped(&mut self, key: &str, element: serde_json::Value, max_len: usize) -> Result<(), Box<dyn Error>> {
+        self.check_key(key)?;
+        let mut array = match self.live(key) {
+            Some(value) => match serde_json::from_str(&self.decode_data(&value.data))? {
+                serde_json::Value::Array(array) => array,
+                _ => return Err(format!("value at '{}' is not a JSON array", key).into()),
+            },
+            None => Vec::new(),
+        };
+
+        array.push(element);
+        if array.len() > max_len {
+            array.drain(..array.len() - max_len);
+        }
+
+        let data = serde_json::to_string(&array)?;
+        self.rewrite_value(key, data);
+        self.save()?;
+        Ok(())
+    }
+
+    /// Distinct elements found in any of the arrays stored at `keys`.
+    pub fn array_union(&self, keys: &[&str]) -> Vec<serde_json::Value> {
+        let mut result = Vec::new();
+        for key in keys {
+            for element in self.json_array(key) {
+                if !result.contains(&element) {
+                    result.push(element);
+                }
+            }
+        }
+        result
+    }
+
+    /// Distinct elements present in every array stored at `keys`.
+    pub fn array_intersection(&self, keys: &[&str]) -> Vec<serde_json::Value> {
+        let Some((first, rest)) = keys.split_first() else {
+            return Vec::new();
+        };
+        let others: Vec<Vec<serde_json::Value>> = rest.iter().map(|key| self.json_array(key)).collect();
+        let mut result = Vec::new();
+        for element in self.json_array(first) {
+            if !result.contains(&element) && others.iter().all(|other| other.contains(&element)) {
+                result.push(element);
+            }
+        }
+        result
+    }
+
+    /// Distinct elements of the first key's array that appear in none of the
+    /// arrays stored at the remaining keys.
+    pub fn array_difference(&self, keys: &[&str]) -> Vec<serde_json::Value> {
+        let Some((first, rest)) = keys.split_first() else {
+            return Vec::new();
+        };
+        let others: Vec<Vec<serde_json::Value>> = rest.iter().map(|key| self.json_array(key)).collect();
+        let mut result = Vec::new();
+        for element in self.json_array(first) {
+            if !result.contains(&element) && !others.iter().any(|other| other.contains(&element)) {
+                result.push(element);
+            }
+        }
+        result
+    }
+
+    /// Splits live keys into those whose value parses as JSON and those
+    /// holding plain strings, each sorted. Useful for finding imported data
+    /// that failed to parse.
+    pub fn classify_values(&self) -> (Vec<String>, Vec<String>) {
+        let (mut json, mut plain): (Vec<_>, Vec<_>) = self.list()
+            .into_iter()
+            .partition(|(_, data)| serde_json::from_str::<serde_json::Value>(data).is_ok());
+        json.sort();
+        plain.sort();
+        (
+            json.into_iter().map(|(key, _)| key).collect(),
+            plain.into_iter().map(|(key, _)| key).collect(),
+        )
+    }
+
+    /// Live entries whose value parses as JSON of type `t`, sorted by key.
+    pub fn filter_by_type(&self, t: ValueType) -> Vec<(String, serde_json::Value)> {
+        let mut entries: Vec<(String, serde_json::Value)> = self.list()
+            .into_iter()
+            .map(|(key, data)| (key, Self::parse_json(&data)))
+            .filter(|(_, value)| ValueType::of(value) == t)
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    /// Merkle root over the live entries in key order, hex encoded. Two
+    /// stores with the same live contents have the same root, however the
+    /// entries were written.
+    pub fn merkle_root(&self) -> String {
+        let mut entries = self.list();
+        entries.sort();
+        merkle::root(&entries)
+    }
+
+    /// Proof that the live entry at `key` is included under `merkle_root`,
+    /// checked with `MerkleProof::verify`.
+    pub fn merkle_proof(&self, key: &str) -> Option<MerkleProof> {
+        let mut entries = self.list();
+        entries.sort();
+        let index = entries.binary_search_by(|(k, _)| k.as_str().cmp(key)).ok()?;
+        Some(merkle::proof(&entries, index))
+    }
+
+    /// CRC32 of each live value by key, so a remote replica can compare
+    /// checksums and fetch only the keys that differ.
+    pub fn key_checksums(&self) -> BTreeMap<String, u32> {
+        self.list()
+            .into_iter()
+            .map(|(key, data)| (key, crc32fast::hash(data.as_bytes())))
+            .collect()
+    }
+
+    /// Live entries with nested JSON objects expanded into dotted keys, e.g.
+    /// `config.db.host`. Arrays, scalars, and empty objects are leaves.
+    pub fn flatten(&self) -> BTreeMap<String, serde_json::Value> {
+        fn walk(prefix: String, value: serde_json::Value, out: &mut BTreeMap<String, serde_json::Value>) {
+            match value {
+                serde_json::Value::Object(map) if !map.is_empty() => {
+                    for (key, child) in map {
+                        walk(format!("{}.{}", prefix, key), child, out);
+                    }
+                }
+                leaf => {
+                    out.insert(prefix, leaf);
+                }
+            }
+        }
+
+        let mut flat = BTreeMap::new();
+        for (key, data) in self.list() {
+            walk(key, Self::parse_json(&data), &mut flat);
+        }
+        flat
+    }
+
+    /// Every live entry with its JSON value, type, and remaining TTL,
+    /// sorted by key.
+    pub fn detailed_list(&self) -> Vec<EntryDetail> {
+        let mut details: Vec<EntryDetail> = self.list()
+            .into_iter()
+            .map(|(key, data)| {
+                let value = Self::parse_json(&data);
+                EntryDetail {
+                    value_type: ValueType::of(&value),
+                    ttl: self.get_ttl(&key),
+                    key,
+                    value,
+                }
+            })
+            .collect();
+        details.sort_by(|a, b| a.key.cmp(&b.key));
+        details
+    }
+
+    /// Count, sum, min, max, and mean over live keys under `prefix` whose
+    /// values parse as finite numbers. Non-numeric values are skipped;
+    /// returns `None` if no value qualifies.
+    pub fn numeric_summary(&self, prefix: &str) -> Option<NumericSummary> {
+        let numbers: Vec<f64> = self.list()
+            .into_iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .filter_map(|(_, data)| data.trim().parse::<f64>().ok())
+            .filter(|n| n.is_finite())
+            .collect();
+        if numbers.is_empty() {
+            return None;
+        }
+
+        let sum: f64 = numbers.iter().sum();
+        Some(NumericSummary {
+            count: numbers.len(),
+            sum,
+            min: numbers.iter().copied().fold(f64::INFINITY, f64::min),
+            max: numbers.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            mean: sum / numbers.len() as f64,
+        })
+    }
+
+    /// A JSON-Schema-style description of the live values: an object schema
+    /// whose properties map each key to its inferred type. Arrays report a
+    /// merged `items` schema, listing every element type if they differ.
+    pub fn infer_schema(&self) -> serde_json::Value {
+        fn type_name(value: &serde_json::Value) -> &'static str {
+            match ValueType::of(value) {
+                ValueType::Null => "null",
+                ValueType::Bool => "boolean",
+                ValueType::Number => "number",
+                ValueType::String => "string",
+                ValueType::Array => "array",
+                ValueType::Object => "object",
+            }
+        }
+
+        fn schema_of(value: &serde_json::Value) -> serde_json::Value {
+            match value {
+                serde_json::Value::Array(elements) => {
+                    let mut items: Vec<serde_json::Value> = Vec::new();
+                    for element in elements {
+                        let schema = schema_of(element);
+                        if !items.contains(&schema) {
+                            items.push(schema);
+                        }
+                    }
+                    match items.len() {
+                        0 => serde_json::json!({ "type": "array" }),
+                        1 => serde_json::json!({ "type": "array", "items": items.remove(0) }),
+                        _ => {
+                            let mut types: Vec<&str> = elements.iter().map(type_name).collect();
+                            types.sort();
+                            types.dedup();
+                            serde_json::json!({ "type": "array", "items": { "type": types } })
+                        }
+                    }
+                }
+                serde_json::Value::Object(map) => {
+                    let properties: serde_json::Map<String, serde_json::Value> = map
+                        .iter()
+                        .map(|(key, child)| (key.clone(), schema_of(child)))
+                        .collect();
+                    serde_json::json!({ "type": "object", "properties": properties })
+                }
+                scalar => serde_json::json!({ "type": type_name(scalar) }),
+            }
+        }
+
+        let properties: serde_json::Map<String, serde_json::Value> = self.list()
+            .into_iter()
+            .map(|(key, data)| (key, schema_of(&Self::parse_json(&data))))
+            .collect();
+        serde_json::json!({ "type": "object", "properties": properties })
+    }
+
+    // Stored data as JSON, treating anything unparseable as a plain string
+    fn parse_json(data: &str) -> serde_json::Value {
+        serde_json::from_str(data).unwrap_or_else(|_| serde_json::Value::String(data.to_string()))
+    }
+
+    // Missing, expired, or non-array values all read as an empty array
+    fn json_array(&self, key: &str) -> Vec<serde_json::Value> {
+        match self.get(key).and_then(|data| serde_json::from_str(&data).ok()) {
+            Some(serde_json::Value::Array(array)) => array,
+            _ => Vec::new(),
+        }
+    }
+
+    /// Re-reads the file, e.g. after another process wrote it, and merges it
+    /// into the in-memory entries instead of replacing them, so local changes
+    /// survive. Conflicting keys are resolved by `strategy`. Keys deleted
+    /// here since the last save stay deleted unless the file holds a write
+    /// made after the deletion and `strategy` prefers the disk. The merged
+    /// store is saved back. Returns how many entries were taken from disk.
+    pub fn reload_merging(&mut self, strategy: MergeStrategy) -> Result<usize, Box<dyn Error>> {
+        let read = match &self.file_path {
+            Some(file_path) => fs::read(file_path),
+            None => Err(io::ErrorKind::NotFound.into()),
+        };
+        let (on_disk, _) = match read {
+            Ok(contents) => Self::decode(&contents, self.now_ms(), self.salvage_malformed)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (HashMap::new(), 0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut adopted = 0;
+        for (key, mut value) in on_disk {
+            if self.is_expired(&value) {
+                continue;
+            }
+            if strategy == MergeStrategy::KeepExisting && self.live(&key).is_some() {
+                continue;
+            }
+            if let Some(&deleted_at) = self.tombstones.get(&key).filter(|_| self.live(&key).is_none()) {
+                if value.updated_at <= deleted_at || strategy == MergeStrategy::KeepExisting {
+                    continue;
+                }
+            }
+            value.seq = self.next_seq;
+            self.next_seq += 1;
+            self.store.insert(key, value);
+            adopted += 1;
+        }
+        self.peak_len = self.peak_len.max(self.store.len());
+
+        self.save()?;
+        Ok(adopted)
+    }
+
+    /// Copies live entries from `source` whose key matches `pred`, keeping
+    /// their expiry. Keys already live in this store are left alone.
+    /// Returns how many entries were copied.
+    pub fn prime_from(&mut self, source: &KvStore, pred: impl Fn(&str) -> bool) -> Result<usize, Box<dyn Error>> {
+        let entries: Vec<(&String, &Value)> = source.store
+            .iter()
+            .filter(|(key, value)| source.is_visible(key, value) && pred(key) && self.live(key).is_none())
+            .collect();
+        for (key, _) in &entries {
+            self.check_key(key)?;
+        }
+
+        let mut primed = 0;
+        for (key, value) in entries {
+            let mut value = value.clone();
+            if self.codec.is_some() || source.codec.is_some() {
+                value.data = self.encode_data(source.decode_data(&value.data).into_owned());
+            }
+            value.seq = self.next_seq;
+            self.next_seq += 1;
+            self.store.insert(key.clone(), value);
+            primed += 1;
+        }
+        self.peak_len = self.peak_len.max(self.store.len());
+
+        if primed > 0 {
+            self.save()?;
+        }
+        Ok(primed)
+    }
+
+    /// Evicts entries until the estimated size (key plus value bytes over
+    /// all entries) is at most `max_bytes`. Keys expiring soonest go first,
+    /// then permanent keys in least-recently-set order. Expired entries are
+    /// dropped without counting. Returns how many live entries were evicted.
+    pub fn evict_to_bytes(&mut self, max_bytes: usize) -> Result<usize, Box<dyn Error>> {
+        let before = self.store.len();
+        let now = self.now_ms();
+        self.store.retain(|_, value| value.effective_expiry().is_none_or(|expires_at| expires_at > now));
+
+        let mut size: usize = self.store.iter().map(|(key, value)| key.len() + value.data.len()).sum();
+        let mut candidates: Vec<(Option<u64>, u64, String)> = self.store
+            .iter()
+            .map(|(key, value)| (value.effective_expiry(), value.seq, key.clone()))
+            .collect();
+        candidates.sort_by_key(|(expires_at, seq, _)| (expires_at.unwrap_or(u64::MAX), *seq));
+
+        let mut evicted = 0;
+        for (_, _, key) in candidates {
+            if size <= max_bytes {
+                break;
+            }
+            if let Some(value) = self.remove_entry(&key) {
+                size -= key.len() + value.data.len();
+                evicted += 1;
+            }
+        }
+
+        if self.store.len() != before {
+            self.save()?;
+        }
+        Ok(evicted)
+    }
+
+    /// Sets a live key to expire `ttl_seconds` from now without changing its
+    /// value, subject to any TTL ceiling. Returns `false` if the key is
+    /// missing or expired.
+    pub fn expire(&mut self, key: &str, ttl_seconds: u64) -> Result<bool, Box<dyn Error>> {
+        let expires_at = self.expiry_for(Some(ttl_seconds));
+        self.update_expiry(key, expires_at)
+    }
+
+    /// Removes a live key's expiry, making it permanent (or giving it the TTL
+    /// ceiling, if one is set). Returns `false` if the key is missing or
+    /// expired.
+    pub fn persist(&mut self, key: &str) -> Result<bool, Box<dyn Error>> {
+        let expires_at = self.expiry_for(None);
+        self.update_expiry(key, expires_at)
+    }
+
+    fn update_expiry(&mut self, key: &str, expires_at: Option<u64>) -> Result<bool, Box<dyn Error>> {
+        if self.live(key).is_none() {
+            return Ok(false);
+        }
+        if let Some(value) = self.store.get_mut(key) {
+            value.expires_at = expires_at;
+            value.sliding_ttl = None;
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Records a failed attempt at processing `key`. Once `max_attempts`
+    /// failures have been recorded the entry is moved to `dlq:<key>` and
+    /// the original removed. Returns whether it was dead-lettered. Setting
+    /// the key again resets its count, but edits made in place, such as
+    /// `increment`, `truncate_value` or `merge_patch`, keep it. A `dlq:` key
+    /// over the `with_max_key_bytes` limit is an error, and the failure isn't
+    /// counted.
+    pub fn fail(&mut self, key: &str, max_attempts: u32) -> Result<bool, Box<dyn Error>> {
+        let Some(attempts) = self.live(key).map(|value| value.attempts) else {
+            return Err(format!("key '{}' not found", key).into());
+        };
+        if attempts.saturating_add(1) >= max_attempts {
+            self.check_key(&format!("dlq:{}", key))?;
+        }
+        let Some(value) = self.store.get_mut(key) else {
+            return Ok(false);
+        };
+        value.attempts = value.attempts.saturating_add(1);
+
+        let dead_lettered = value.attempts >= max_attempts;
+        if dead_lettered {
+            if let Some(value) = self.remove_entry(key) {
+                self.store.insert(format!("dlq:{}", key), value);
+            }
+        }
+        self.save()?;
+        Ok(dead_lettered)
+    }
+
+    /// Moves the value at `from` to `to`, keeping its TTL and overwriting
+    /// any existing `to`. Returns `false` if `from` is missing or expired.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<bool, Box<dyn Error>> {
+        self.check_key(to)?;
+        if self.live(from).is_none() {
+            return Ok(false);
+        }
+        if let Some(value) = self.remove_entry(from) {
+            self.store.insert(to.to_string(), value);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Applies every `(from, to)` rename in `mapping` at once, keeping values
+    /// and TTLs, so swaps like `a→b, b→a` work. Nothing is renamed if two
+    /// renames share a target or a target is a live key not itself being
+    /// renamed away. Missing sources are skipped. Returns how many keys were
+    /// renamed.
+    pub fn rename_many(&mut self, mapping: &[(&str, &str)]) -> Result<usize, Box<dyn Error>> {
+        let mut targets = HashSet::new();
+        for (from, to) in mapping {
+            self.check_key(to)?;
+            if !targets.insert(*to) {
+                return Err(format!("more than one key would be renamed to '{}'", to).into());
+            }
+            if self.live(to).is_some() && !mapping.iter().any(|(other, _)| other == to) {
+                return Err(format!("cannot rename '{}' to '{}': key already exists", from, to).into());
+            }
+        }
+
+        let renames: Vec<(&str, &str)> = mapping
+            .iter()
+            .copied()
+            .filter(|(from, _)| self.live(from).is_some())
+            .collect();
+        // Remove everything first so renames can't clobber keys still to be moved
+        let moved: Vec<(&str, Value)> = renames
+            .into_iter()
+            .filter_map(|(from, to)| self.remove_entry(from).map(|value| (to, value)))
+            .collect();
+        let count = moved.len();
+        for (to, value) in moved {
+            self.store.insert(to.to_string(), value);
+        }
+
+        if count > 0 {
+            self.save()?;
+        }
+        Ok(count)
+    }
+
+    /// Renames every live key starting with `old_prefix` to start with
+    /// `new_prefix` instead, keeping values and TTLs. Existing keys under the
+    /// new prefix are overwritten. Returns how many keys were renamed.
+    pub fn reprefix(&mut self, old_prefix: &str, new_prefix: &str) -> Result<usize, Box<dyn Error>> {
+        let keys: Vec<String> = self.store
+            .iter()
+            .filter(|(key, value)| key.starts_with(old_prefix) && self.is_visible(key, value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &keys {
+            self.check_key(&format!("{}{}", new_prefix, &key[old_prefix.len()..]))?;
+        }
+
+        // Remove everything first so renames can't clobber keys still to be moved
+        let moved: Vec<(String, Value)> = keys
+            .into_iter()
+            .filter_map(|key| self.remove_entry(&key).map(|value| (key, value)))
+            .collect();
+        let count = moved.len();
+        for (key, value) in moved {
+            let renamed = format!("{}{}", new_prefix, &key[old_prefix.len()..]);
+            self.store.insert(renamed, value);
+        }
+
+        if count > 0 {
+            self.save()?;
+        }
+        Ok(count)
+    }
+
+    /// Keeps only the live entries for which `pred(key, value)` returns true
+    /// and saves once. Expired entries are dropped without being offered to
+    /// the predicate and are not included in the returned removal count;
+    /// macros are kept without being offered to it.
+    pub fn retain<F: Fn(&str, &str) -> bool>(&mut self, pred: F) -> Result<usize, Box<dyn Error>> {
+        let now = self.now_ms();
+        let before = self.store.len();
+        let mut removed = Vec::new();
+        let codec = self.codec.clone();
+        self.store.retain(|key, value| {
+            if value.effective_expiry().is_some_and(|expires_at| expires_at <= now) {
+                return false;
+            }
+            if is_reserved(key) {
+                return true;
+            }
+            let keep = match &codec {
+                Some(codec) => pred(key, &codec.decode(&value.data)),
+                None => pred(key, &value.data),
+            };
+            if !keep {
+                removed.push(key.clone());
+            }
+            keep
+        });
+        let count = removed.len();
+        self.tombstones.extend(removed.into_iter().map(|key| (key, now)));
+
+        if self.store.len() != before {
+            self.save()?;
+        }
+        Ok(count)
+    }
+
+    /// Adds `by` to the integer stored at `key` and returns the new value.
+    /// A missing key counts as 0; a non-integer value is an error and is
+    /// left unchanged. The key's expiry is kept.
+    pub fn increment(&mut self, key: &str, by: i64) -> Result<i64, Box<dyn Error>> {
+        let current = match self.live(key) {
+            Some(value) => Some(self.decode_data(&value.data).trim().parse::<i64>()
+                .map_err(|_| format!("value at '{}' is not an integer", key))?),
+            None => None,
+        };
+        let updated = current
+            .unwrap_or(0)
+            .checked_add(by)
+            .ok_or_else(|| format!("incrementing '{}' would overflow", key))?;
+
+        if current.is_none() {
+            self.check_key(key)?;
+        }
+        self.rewrite_value(key, updated.to_string());
+
+        match (self.counter_flush_interval, self.last_save) {
+            (Some(interval), Some(last)) if last.elapsed() < interval => self.dirty = true,
+            _ => self.save()?,
+        }
+        Ok(updated)
+    }
+
+    /// Subtracts `by` from the integer stored at `key`; see `increment`.
+    pub fn decrement(&mut self, key: &str, by: i64) -> Result<i64, Box<dyn Error>> {
+        let by = by.checked_neg().ok_or_else(|| format!("decrementing '{}' would overflow", key))?;
+        self.increment(key, by)
+    }
+
+    /// Subtracts `delta` from the integer at `key` without going below
+    /// `floor`, and returns the new value. A value already below `floor` is
+    /// left as it is, and a missing key is set to `0 - delta`, or to `floor`
+    /// if that is higher. The key's expiry is kept.
+    pub fn decrement_floor(&mut self, key: &str, delta: i64, floor: i64) -> Result<i64, Box<dyn Error>> {
+        let current = match self.live(key) {
+            Some(value) => {
+                let current = self.decode_data(&value.data).trim().parse::<i64>()
+                    .map_err(|_| format!("value at '{}' is not an integer", key))?;
+                if current < floor {
+                    return Ok(current);
+                }
+                current
+            }
+            None => 0,
+        };
+        let target = current.saturating_sub(delta).max(floor);
+        let by = target
+            .checked_sub(current)
+            .ok_or_else(|| format!("decrementing '{}' would overflow", key))?;
+        self.increment(key, by)
+    }
+
+    /// Writes any changes that are still only in memory, such as writes made
+    /// with autosave off, buffered counter increments, or writes kept after
+    /// a failed save. Errors are returned even when unsynced writes are
+    /// tolerated.
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.dirty {
+            self.write_file()?;
+        }
+        Ok(())
+    }
+
+    /// Deletes every key in `keys`, saving once. Returns the removed values
+    /// in the same order, with `None` for keys that were not present.
+    pub fn delete_many(&mut self, keys: &[&str]) -> Result<Vec<Option<String>>, Box<dyn Error>> {
+        let removed: Vec<Option<String>> = keys
+            .iter()
+            .map(|key| self.remove_entry(key).map(|v| self.decode_data(&v.data).into_owned()))
+            .collect();
+        self.save()?;
+        Ok(removed)
+    }
+
+    pub fn delete(&mut self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let result = self.remove_entry(key).map(|v| self.decode_data(&v.data).into_owned());
+        self.save()?;
+        Ok(result)
+    }
+
+    /// Removes every entry except macros and saves the store.
+    pub fn clear(&mut self) -> Result<(), Box<dyn Error>> {
+        let now = self.now_ms();
+        let mut cleared = Vec::new();
+        self.store.retain(|key, _| {
+            if is_reserved(key) {
+                return true;
+            }
+            cleared.push(key.clone());
+            false
+        });
+        self.tombstones.extend(cleared.into_iter().map(|key| (key, now)));
+        self.save()
+    }
+
+    // Removes `key` and records the deletion for `reload_merging`
+    fn remove_entry(&mut self, key: &str) -> Option<Value> {
+        let removed = self.store.remove(key)?;
+        let now = self.now_ms();
+        self.tombstones.insert(key.to_string(), now);
+        Some(removed)
+    }
+
+    /// Runs `f` with saving suspended, then writes its changes in a single
+    /// save instead of one per write. Nested calls save once, when the
+    /// outermost one returns.
+    pub fn without_persistence<F, T>(&mut self, f: F) -> Result<T, Box<dyn Error>>
+    where
+        F: FnOnce(&mut Self) -> T,
+    {
+        let was_suspended = std::mem::replace(&mut self.saves_suspended, true);
+        let result = f(self);
+        self.saves_suspended = was_suspended;
+        if self.dirty && !was_suspended {
+            self.save()?;
+        }
+        Ok(result)
+    }
+
+    fn save(&mut self) -> Result<(), Box<dyn Error>> {
+        let coalesced = self.coalesce_saves
+            && self.last_save.is_some_and(|last| last.elapsed() < SAVE_COALESCE_WINDOW);
+        if self.saves_suspended || self.autosave_disabled || coalesced {
+            self.dirty = true;
+            return Ok(());
+        }
+        match self.write_file() {
+            Err(_) if self.tolerate_save_failures => {
+                self.dirty = true;
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn write_file(&mut self) -> Result<(), Box<dyn Error>> {
+        let result = self.try_write_file();
+        self.sync_error = result.as_ref().err().map(|e| e.to_string());
+        if result.is_ok() {
+            self.publish_changes();
+        }
+        result
+    }
+
+    /// Starts a feed of every change to this store, for replicating it into
+    /// a follower with `apply_change`. The feed opens with a `Set` for each
+    /// live key, then reports changes as they are saved. Replaces any
+    /// earlier feed.
+    pub fn change_feed(&mut self) -> Receiver<Change> {
+        let (sender, receiver) = mpsc::channel();
+        self.feed = Some(ChangeFeed { sender, published: HashMap::new() });
+        self.publish_changes();
+        receiver
+    }
+
+    // Like `change_feed`, but treats the current entries as already
+    // published, so only later changes are reported
+    pub(crate) fn change_feed_from_now(&mut self) -> Receiver<Change> {
+        let (sender, receiver) = mpsc::channel();
+        self.feed = Some(ChangeFeed { sender, published: self.feed_state() });
+        receiver
+    }
+
+    // Swaps in `other`'s entries, as when following a file another process
+    // rewrote, and publishes the difference on the feed
+    pub(crate) fn replace_entries(&mut self, mut other: KvStore) {
+        self.store = std::mem::take(&mut other.store);
+        self.publish_changes();
+    }
+
+    /// Replays a change from another store's `change_feed`.
+    pub fn apply_change(&mut self, change: Change) -> Result<(), Box<dyn Error>> {
+        match change {
+            Change::Set { key, value, expires_at } => {
+                self.check_key(&key)?;
+                self.insert_value(key, value, expires_at);
+            }
+            Change::Delete { key } => {
+                self.remove_entry(&key);
+            }
+            Change::Expire { key, expires_at } => {
+                if let Some(value) = self.store.get_mut(&key) {
+                    value.expires_at = expires_at;
+                    value.sliding_ttl = None;
+                }
+            }
+        }
+        self.save()
+    }
+
+    // Sends whatever differs from the state last published on the feed
+    pub(crate) fn publish_changes(&mut self) {
+        let Some(mut feed) = self.feed.take() else {
+            return;
+        };
+        let current = self.feed_state();
+
+        let mut changes = Vec::new();
+        for (key, (value, expires_at)) in &current {
+            let set = || Change::Set { key: key.clone(), value: value.clone(), expires_at: *expires_at };
+            match feed.published.get(key) {
+                None => changes.push(set()),
+                Some((old_value, _)) if old_value != value => changes.push(set()),
+                Some((_, old_expiry)) if old_expiry != expires_at => {
+                    changes.push(Change::Expire { key: key.clone(), expires_at: *expires_at });
+                }
+                Some(_) => {}
+            }
+        }
+        for key in feed.published.keys().filter(|key| !current.contains_key(*key)) {
+            changes.push(Change::Delete { key: key.clone() });
+        }
+
+        // A dropped receiver ends the feed
+        if changes.into_iter().all(|change| feed.sender.send(change).is_ok()) {
+            feed.published = current;
+            self.feed = Some(feed);
+        }
+    }
+
+    // Live entries as a change feed sees them: value and effective expiry
+    fn feed_state(&self) -> HashMap<String, (String, Option<u64>)> {
+        self.store
+            .iter()
+            .filter(|(key, value)| self.is_visible(key, value))
+            .map(|(key, value)| (key.clone(), (self.decode_data(&value.data).into_owned(), value.effective_expiry())))
+            .collect()
+    }
+
+    // Writes a sibling temp file and renames it over the real one, so a save
+    // interrupted part way leaves the previous snapshot intact
+    fn try_write_file(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(file_path) = self.file_path.clone() else {
+            self.dirty = false;
+            self.tombstones.clear();
+            return Ok(());
+        };
+        let mut temp_name = file_path.clone().into_os_string();
+        temp_name.push(format!(".{}-{}.tmp", process::id(), TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let temp_path = PathBuf::from(temp_name);
+
+        let result = self.write_temp_file(&temp_path).and_then(|written| {
+            fs::rename(&temp_path, &file_path)?;
+            Ok(written)
+        });
+        let written = match result {
+            Ok(written) => written,
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path);
+                return Err(e);
+            }
+        };
+        self.bytes_written.fetch_add(written as u64, Ordering::Relaxed);
+        self.dirty = false;
+        self.tombstones.clear();
+        self.last_save = Some(Instant::now());
+        Ok(())
+    }
+
+    // Streams the snapshot straight into the file rather than building it
+    // in memory first, and syncs it before returning the bytes written
+    fn write_temp_file(&self, path: &Path) -> Result<usize, Box<dyn Error>> {
+        let file = File::create(path)?;
+        let mut writer = CountingWriter::new(BufWriter::new(file));
+        self.write_snapshot(&mut writer)?;
+        let written = writer.written;
+        writer.inner.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(written)
+    }
+
+    pub fn list(&self) -> Vec<(String, String)> {
+        self.store
+            .iter()
+            .filter(|(key, value)| self.is_visible(key, value))
+            .map(|(k, v)| (k.clone(), self.decode_data(&v.data).into_owned()))
+            .collect()
+    }
+
+    /// Like `list`, but copies out at most `max` entries, in no particular
+    /// order. The flag is `true` if live entries were left out.
+    pub fn list_limited(&self, max: usize) -> (Vec<(String, String)>, bool) {
+        let mut live = self.store.iter().filter(|(key, value)| self.is_visible(key, value));
+        let entries = live
+            .by_ref()
+            .take(max)
+            .map(|(k, v)| (k.clone(), self.decode_data(&v.data).into_owned()))
+            .collect();
+        (entries, live.next().is_some())
+    }
+
+    /// Live keys only; unlike `list`, value data is never read or cloned.
+    pub fn keys(&self) -> Vec<String> {
+        self.store
+            .iter()
+            .filter(|(key, value)| self.is_visible(key, value))
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    /// Filters, sorts, and pages the live entries in one pass; see
+    /// `QueryOptions`.
+    pub fn query(&self, opts: QueryOptions) -> QueryResult {
+        let mut matches: Vec<(&String, &Value)> = self.store
+            .iter()
+            .filter(|(key, value)| {
+                self.is_visible(key, value)
+                    && opts.prefix.as_deref().is_none_or(|prefix| key.starts_with(prefix))
+                    && opts.value_contains.as_deref().is_none_or(|needle| self.decode_data(&value.data).contains(needle))
+            })
+            .collect();
+        match opts.sort {
+            QuerySort::Key => matches.sort_by(|a, b| a.0.cmp(b.0)),
+            QuerySort::Ttl => matches.sort_by_key(|(key, value)| (value.effective_expiry().unwrap_or(u64::MAX), *key)),
+        }
+
+        let total = matches.len();
+        let entries = matches
+            .into_iter()
+            .skip(opts.offset)
+            .take(opts.limit.unwrap_or(usize::MAX))
+            .map(|(key, value)| (key.clone(), self.decode_data(&value.data).into_owned()))
+            .collect();
+        QueryResult { entries, total }
+    }
+
+    /// Live keys present in both this store and `other`, sorted.
+    pub fn common_keys(&self, other: &KvStore) -> Vec<String> {
+        let mut keys: Vec<String> = self.keys()
+            .into_iter()
+            .filter(|key| other.live(key).is_some())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// Like `list`, but only keys starting with `prefix`. An empty prefix
+    /// lists everything.
+    pub fn list_prefix(&self, prefix: &str) -> Vec<(String, String)> {
+        self.store
+            .iter()
+            .filter(|(key, value)| key.starts_with(prefix) && self.is_visible(key, value))
+            .map(|(k, v)| (k.clone(), self.decode_data(&v.data).into_owned()))
+            .collect()
+    }
+
+    /// Like `keys`, but only keys starting with `prefix`.
+    pub fn keys_with_prefix(&self, prefix: &str) -> Vec<String> {
+        self.store
+            .iter()
+            .filter(|(key, value)| key.starts_with(prefix) && self.is_visible(key, value))
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    /// The `n` prefixes (the part of each key before the first `sep`) with
+    /// the most live keys, largest first; ties are broken by prefix. Keys
+    /// without `sep` count under their whole key.
+    pub fn top_prefixes(&self, sep: char, n: usize) -> Vec<(String, usize)> {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for (key, value) in &self.store {
+            if self.is_visible(key, value) {
+                let prefix = key.split(sep).next().unwrap_or(key);
+                *counts.entry(prefix).or_insert(0) += 1;
+            }
+        }
+
+        let mut counts: Vec<(String, usize)> = counts
+            .into_iter()
+            .map(|(prefix, count)| (prefix.to_string(), count))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts.truncate(n);
+        counts
+    }
+
+    /// Returns up to `n` live keys, most recently set first.
+    pub fn recent_keys(&self, n: usize) -> Vec<String> {
+        let mut entries: Vec<(&String, &Value)> = self.store
+            .iter()
+            .filter(|(key, value)| self.is_visible(key, value))
+            .collect();
+        entries.sort_by_key(|(_, value)| std::cmp::Reverse(value.seq));
+        entries.into_iter().take(n).map(|(k, _)| k.clone()).collect()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            entries: self.list().into_iter().collect(),
+        }
+    }
+
+    /// Reports keys added, removed, or changed since `base` was captured.
+    pub fn diff_from(&self, base: &Snapshot) -> StoreDiff {
+        let current = self.snapshot().entries;
+        let mut diff = StoreDiff::default();
+
+        for (key, value) in &current {
+            match base.entries.get(key) {
+                None => diff.added.push(key.clone()),
+                Some(old) if old != value => diff.changed.push(key.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.removed = base.entries
+            .keys()
+            .filter(|key| !current.contains_key(*key))
+            .cloned()
+            .collect();
+
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort();
+        diff
+    }
+
+    /// Live entries last written at or after `start` and before `end`, in
+    /// milliseconds since the Unix epoch, sorted by key. For incremental
+    /// exports, pass the previous export's `end` as the next `start`.
+    pub fn modified_between(&self, start: u64, end: u64) -> Vec<(String, String)> {
+        let mut entries: Vec<(String, String)> = self.store
+            .iter()
+            .filter(|(key, value)| (start..end).contains(&value.updated_at) && self.is_visible(key, value))
+            .map(|(key, value)| (key.clone(), self.decode_data(&value.data).into_owned()))
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    /// Live keys whose value is exactly `value`, sorted.
+    pub fn keys_with_value(&self, value: &str) -> Vec<String> {
+        let mut keys: Vec<String> = self.list()
+            .into_iter()
+            .filter(|(_, data)| data == value)
+            .map(|(key, _)| key)
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// Live keys whose value differs from `baseline` or is missing from it,
+    /// sorted. Keys only in `baseline` are not reported.
+    pub fn changed_against(&self, baseline: &HashMap<String, String>) -> Vec<String> {
+        let mut keys: Vec<String> = self.list()
+            .into_iter()
+            .filter(|(key, value)| baseline.get(key) != Some(value))
+            .map(|(key, _)| key)
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    // Entry for `key` if present and unexpired, without counting as a read
+    pub(crate) fn live(&self, key: &str) -> Option<&Value> {
+        self.store.get(key).filter(|value| !self.is_expired(value))
+    }
+
+    pub(crate) fn live_entries(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.store
+            .iter()
+            .filter(|(key, value)| self.is_visible(key, value))
+            .map(|(key, value)| (key.as_str(), value))
+    }
+
+    // Live and not a macro: what listings and counts show
+    fn is_visible(&self, key: &str, value: &Value) -> bool {
+        !is_reserved(key) && !self.is_expired(value)
+    }
+
+    fn is_expired(&self, value: &Value) -> bool {
+        if let Some(expires_at) = value.effective_expiry() {
+            expires_at <= self.now_ms()
+        } else {
+            false
+        }
+    }
+
+    /// Removes every expired entry and returns the removed keys, sorted, so
+    /// callers can log evictions or invalidate caches. Runs automatically
+    /// when the store is opened. Saves only if something expired.
+    pub fn cleanup_expired(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut expired_keys: Vec<String> = self.store
+            .iter()
+            .filter(|(_, value)| self.is_expired(value))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        // Nothing to persist, so don't rewrite the file on every startup
+        if expired_keys.is_empty() {
+            return Ok(expired_keys);
+        }
+        for key in &expired_keys {
+            self.store.remove(key);
+        }
+
+        self.save()?;
+        expired_keys.sort();
+        Ok(expired_keys)
+    }
+
+    // Helper method to get TTL information. Partial seconds round up, so a
+    // key reported with 0 seconds left has already expired
+    pub fn get_ttl(&self, key: &str) -> Option<u64> {
+        self.get_ttl_ms(key).map(|ttl_ms| ttl_ms.div_ceil(1000))
+    }
+
+    /// Remaining TTL of a live key in milliseconds.
+    pub fn get_ttl_ms(&self, key: &str) -> Option<u64> {
+        self.store.get(key).and_then(|value| {
+            if self.is_expired(value) {
+                None
+            } else {
+                value.effective_expiry().map(|expires_at| expires_at.saturating_sub(self.now_ms()))
+            }
+        })
+    }
+
+    /// Live entries as stored: with a codec, `data` is still encoded.
+    pub fn get_all(&self) -> Vec<(String, Value)> {
+        self.store
+            .iter()
+            .filter(|(key, value)| self.is_visible(key, value))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    pub fn read_view(&self) -> ReadView<'_> {
+        ReadView { store: self }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store.keys().all(|key| is_reserved(key))
+    }
+
+    /// Entries held, not counting macros. Expired entries count until they
+    /// are dropped.
+    pub fn len(&self) -> usize {
+        self.store.keys().filter(|key| !is_reserved(key)).count()
+    }
+
+    /// Highest `len()` observed since the store was opened or the peak was
+    /// last reset.
+    pub fn peak_len(&self) -> usize {
+        self.peak_len
+    }
+
+    pub fn reset_peak(&mut self) {
+        self.peak_len = self.len();
+    }
+
+    /// The store's counters in the Prometheus text exposition format, ready
+    /// to serve from a metrics endpoint.
+    pub fn prometheus_metrics(&self) -> String {
+        let live: Vec<&Value> = self.store.iter().filter(|(key, value)| self.is_visible(key, value)).map(|(_, value)| value).collect();
+        let with_ttl = live.iter().filter(|value| value.effective_expiry().is_some()).count();
+        let metrics: [(&str, &str, &str, u64); 7] = [
+            ("kvstore_keys_total", "gauge", "Live keys in the store.", live.len() as u64),
+            ("kvstore_keys_with_ttl", "gauge", "Live keys that have an expiry.", with_ttl as u64),
+            ("kvstore_keys_peak", "gauge", "Highest key count since opening or the last peak reset.", self.peak_len as u64),
+            ("kvstore_write_bytes_total", "counter", "Bytes written to disk by saves.", self.write_bytes_total()),
+            ("kvstore_load_expired_total", "counter", "Entries dropped as expired when the store was opened.", self.load_report.expired as u64),
+            ("kvstore_load_malformed_total", "counter", "Malformed entries skipped when the store was opened.", self.load_report.malformed as u64),
+            ("kvstore_unsynced", "gauge", "1 if the last save failed and changes are only in memory.", self.sync_error.is_some() as u64),
+        ];
+
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value));
+        }
+        out
+    }
+}
+
+// Pending changes (autosave off, buffered counters, or a failed save) are
+// written out when the store goes away
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("Failed to save pending changes: {}", e);
+        }
+    }
+}
+
+/// Borrowed, read-only view of a `KvStore`. Holding one keeps the store
+/// immutably borrowed, so no mutation can happen while it is alive.
+#[derive(Debug, Clone, Copy)]
+pub struct ReadView<'a> {
+    store: &'a KvStore,
+}
+
+impl ReadView<'_> {
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.store.get(key)
+    }
+
+    pub fn list(&self) -> Vec<(String, String)> {
+        self.store.list()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.store.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
+    pub fn get_ttl(&self, key: &str) -> Option<u64> {
+        self.store.get_ttl(key)
+    }
+}
+
+// Splits CSV text into records, handling quoted fields with embedded commas,
+// quotes (doubled), and line breaks. Blank lines are skipped.
+fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, Box<dyn Error>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, in_quotes) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            ('"', true) => in_quotes = false,
+            ('"', false) if field.is_empty() => in_quotes = true,
+            (',', false) => record.push(std::mem::take(&mut field)),
+            ('\r', false) if chars.peek() == Some(&'\n') => {}
+            ('\n', false) => {
+                record.push(std::mem::take(&mut field));
+                if record == [""] {
+                    record.clear();
+                } else {
+                    records.push(std::mem::take(&mut record));
+                }
+            }
+            (c, _) => field.push(c),
+        }
+    }
+    if in_quotes {
+        return Err("unterminated quoted field in CSV".into());
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    // A fresh directory under the system temp dir, removed when dropped.
+    // Declare it before any store using it so the store is dropped first.
+    pub(crate) struct TempDir(pub(crate) PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new() -> Self {
+            static NEXT: AtomicU64 = AtomicU64::new(0);
+            let path = std::env::temp_dir().join(format!("kv-test-{}-{}", process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        pub(crate) fn config(&self) -> StorageConfig {
+            StorageConfig { directory: self.0.clone(), ..StorageConfig::default() }
+        }
+
+        pub(crate) fn open(&self) -> KvStore {
+            KvStore::with_config(self.config()).unwrap()
+        }
+
+        pub(crate) fn json_path(&self) -> PathBuf {
+            self.0.join("kv_store.json")
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // A clock at an arbitrary fixed time, shared with the store under test
+    fn manual_clock() -> Arc<ManualClock> {
+        Arc::new(ManualClock::at_millis(1_700_000_000_000))
+    }
+
+    fn set(store: &mut KvStore, key: &str, value: &str) {
+        store.set(key.to_string(), value.to_string()).unwrap();
+    }
+
+    fn sorted_keys(store: &KvStore) -> Vec<String> {
+        let mut keys = store.keys();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn bincode_snapshot_round_trips() {
+        let dir = TempDir::new();
+        let config = StorageConfig { format: StorageFormat::Bincode, ..dir.config() };
+        let mut store = KvStore::with_config(config.clone()).unwrap();
+        set(&mut store, "plain", "value");
+        store.set_with_ttl("ttl".to_string(), "x".to_string(), Some(60)).unwrap();
+        drop(store);
+
+        let contents = fs::read(dir.0.join("kv_store.bin")).unwrap();
+        assert_eq!(&contents[..4], BINARY_MAGIC);
+        assert_eq!(contents[4], BINARY_VERSION);
+
+        let reopened = KvStore::with_config(config).unwrap();
+        assert_eq!(reopened.get("plain").as_deref(), Some("value"));
+        assert!(reopened.get_ttl("ttl").is_some_and(|ttl| ttl > 0 && ttl <= 60));
+    }
+
+    #[test]
+    fn legacy_json_file_still_loads() {
+        let dir = TempDir::new();
+        fs::write(dir.json_path(), r#"{"a":{"data":"1","expires_at":null},"b":{"data":"2","expires_at":99999999999}}"#).unwrap();
+
+        let store = dir.open();
+        assert_eq!(store.get("a").as_deref(), Some("1"));
+        assert_eq!(store.get("b").as_deref(), Some("2"));
+        assert!(store.get_ttl("b").is_some());
+    }
+
+    #[test]
+    fn loading_sniffs_the_header_regardless_of_file_name() {
+        let dir = TempDir::new();
+        let mut store = dir.open().with_format(StorageFormat::Bincode);
+        set(&mut store, "k", "v");
+        drop(store);
+
+        assert!(fs::read(dir.json_path()).unwrap().starts_with(BINARY_MAGIC));
+        assert_eq!(dir.open().get("k").as_deref(), Some("v"));
+    }
+
+    #[test]
+    fn unknown_binary_version_is_an_error() {
+        let dir = TempDir::new();
+        let mut contents = BINARY_MAGIC.to_vec();
+        contents.push(BINARY_VERSION + 1);
+        fs::write(dir.json_path(), contents).unwrap();
+
+        let error = KvStore::with_config(dir.config()).unwrap_err();
+        assert!(error.to_string().contains("unsupported snapshot version"));
+    }
+
+    #[test]
+    fn seed_defaults_only_fills_absent_keys() {
+        let dir = TempDir::new();
+        let mut store = dir.open();
+        set(&mut store, "theme", "dark");
+
+        let seeded = store.seed_defaults(&[("theme", "light"), ("lang", "en")]).unwrap();
+        assert_eq!(seeded, 1);
+        assert_eq!(store.get("theme").as_deref(), Some("dark"));
+        assert_eq!(store.get("lang").as_deref(), Some("en"));
+
+        drop(store);
+        let mut reopened = dir.open();
+        assert_eq!(reopened.seed_defaults(&[("theme", "light"), ("lang", "fr")]).unwrap(), 0);
+        assert_eq!(reopened.get("lang").as_deref(), Some("en"));
+    }
+
+    #[test]
+    fn relative_ttls_restart_at_load() {
+        let dir = TempDir::new();
+        let clock = manual_clock();
+        let config = StorageConfig { clock: clock.clone(), ttl_mode: TtlMode::Relative, ..dir.config() };
+        let mut store = KvStore::with_config(config.clone()).unwrap();
+        store.set_with_ttl("k".to_string(), "v".to_string(), Some(60)).unwrap();
+        clock.advance(Duration::from_secs(20));
+        set(&mut store, "other", "x");
+        drop(store);
+
+        let saved: serde_json::Value = serde_json::from_slice(&fs::read(dir.json_path()).unwrap()).unwrap();
+        assert_eq!(saved["ttl_mode"], "relative");
+        assert_eq!(saved["entries"]["k"]["expires_at_ms"], 40_000);
+
+        // Time between the save and the load is not counted
+        clock.advance(Duration::from_secs(30));
+        let reopened = KvStore::with_config(config).unwrap();
+        assert_eq!(reopened.get_ttl_ms("k"), Some(40_000));
+        clock.advance(Duration::from_secs(39));
+        assert_eq!(reopened.get_ttl("k"), Some(1));
+    }
+
+    #[test]
+    fn opening_a_relative_file_saves_it_relative() {
+        let dir = TempDir::new();
+        let snapshot = r#"{"ttl_mode":"relative","entries":{"gone":{"data":"x","expires_at_ms":0},"kept":{"data":"v","expires_at_ms":60000}}}"#;
+        fs::write(dir.json_path(), snapshot).unwrap();
+
+        let config = StorageConfig { clock: manual_clock(), ttl_mode: TtlMode::Relative, ..dir.config() };
+        let store = KvStore::with_config(config).unwrap();
+        assert_eq!(store.load_report().expired, 1);
+
+        // Dropping `gone` saved the file; `kept` must still be stored relative
+        let saved: serde_json::Value = serde_json::from_slice(&fs::read(dir.json_path()).unwrap()).unwrap();
+        assert_eq!(saved["ttl_mode"], "relative");
+        assert_eq!(saved["entries"].as_object().unwrap().len(), 1);
+        assert_eq!(saved["entries"]["kept"]["expires_at_ms"], 60_000);
+    }
+
+    #[test]
+    fn absolute_ttls_expire_by_the_loading_clock() {
+        let dir = TempDir::new();
+        let clock = manual_clock();
+        let config = StorageConfig { clock: clock.clone(), ..dir.config() };
+        let mut store = KvStore::with_config(config.clone()).unwrap();
+        store.set_with_ttl("k".to_string(), "v".to_string(), Some(60)).unwrap();
+        drop(store);
+
+        clock.advance(Duration::from_secs(61));
+        let reopened = KvStore::with_config(config).unwrap();
+        assert_eq!(reopened.get("k"), None);
+        assert_eq!(reopened.load_report().expired, 1);
+    }
+
+    #[test]
+    fn huge_relative_ttl_saturates_on_load() {
+        let dir = TempDir::new();
+        let snapshot = format!(r#"{{"ttl_mode":"relative","entries":{{"k":{{"data":"v","expires_at_ms":{}}}}}}}"#, u64::MAX);
+        fs::write(dir.json_path(), snapshot).unwrap();
+
+        let store = dir.open();
+        assert_eq!(store.get("k").as_deref(), Some("v"));
+        assert_eq!(store.store["k"].expires_at, Some(u64::MAX));
+    }
+
+    #[test]
+    fn recent_keys_lists_most_recently_set_first() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "a", "1");
+        set(&mut store, "b", "2");
+        set(&mut store, "c", "3");
+        set(&mut store, "a", "4");
+
+        assert_eq!(store.recent_keys(2), vec!["a", "c"]);
+        assert_eq!(store.recent_keys(10), vec!["a", "c", "b"]);
+    }
+
+    #[test]
+    fn append_capped_drops_oldest_elements() {
+        let mut store = KvStore::in_memory();
+        for n in 1..=4 {
+            store.append_capped("log", serde_json::json!(n), 3).unwrap();
+        }
+        assert_eq!(store.get("log").as_deref(), Some("[2,3,4]"));
+    }
+
+    #[test]
+    fn append_capped_rejects_non_arrays_and_keeps_ttl() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "scalar", "5");
+        assert!(store.append_capped("scalar", serde_json::json!(1), 3).is_err());
+        assert_eq!(store.get("scalar").as_deref(), Some("5"));
+
+        store.set_with_ttl("log".to_string(), "[]".to_string(), Some(60)).unwrap();
+        store.append_capped("log", serde_json::json!("x"), 3).unwrap();
+        assert_eq!(store.get("log").as_deref(), Some(r#"["x"]"#));
+        assert!(store.get_ttl("log").is_some());
+    }
+
+    #[test]
+    fn array_set_operations() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "a", "[1,2,3,3]");
+        set(&mut store, "b", "[2,3,4]");
+        set(&mut store, "c", "[3]");
+        set(&mut store, "text", "not an array");
+        let json = |values: &[i64]| values.iter().map(|v| serde_json::json!(v)).collect::<Vec<_>>();
+
+        assert_eq!(store.array_union(&["a", "b"]), json(&[1, 2, 3, 4]));
+        assert_eq!(store.array_intersection(&["a", "b", "c"]), json(&[3]));
+        assert_eq!(store.array_difference(&["a", "b"]), json(&[1]));
+        assert_eq!(store.array_union(&["missing", "text"]), json(&[]));
+        assert_eq!(store.array_intersection(&[]), json(&[]));
+    }
+
+    #[test]
+    fn peak_len_tracks_the_high_water_mark() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "a", "1");
+        set(&mut store, "b", "2");
+        store.delete("a").unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.peak_len(), 2);
+
+        store.reset_peak();
+        assert_eq!(store.peak_len(), 1);
+    }
+
+    #[test]
+    fn diff_from_reports_added_removed_and_changed_keys() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "same", "1");
+        set(&mut store, "changed", "1");
+        set(&mut store, "removed", "1");
+        let base = store.snapshot();
+
+        set(&mut store, "changed", "2");
+        store.delete("removed").unwrap();
+        set(&mut store, "added", "1");
+        set(&mut store, "same", "1");
+
+        assert_eq!(store.diff_from(&base), StoreDiff {
+            added: vec!["added".to_string()],
+            removed: vec!["removed".to_string()],
+            changed: vec!["changed".to_string()],
+        });
+    }
+
+    #[test]
+    fn pre_epoch_clock_reads_as_the_epoch() {
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH - Duration::from_secs(3600)));
+        let mut store = KvStore::in_memory().with_clock(clock.clone());
+        store.set_with_ttl("k".to_string(), "v".to_string(), Some(10)).unwrap();
+
+        assert_eq!(store.store["k"].expires_at, Some(10_000));
+        assert_eq!(store.store["k"].updated_at, 0);
+        assert_eq!(store.get_ttl("k"), Some(10));
+
+        clock.set(UNIX_EPOCH + Duration::from_secs(11));
+        assert_eq!(store.get("k"), None);
+    }
+
+    #[test]
+    fn pre_epoch_clock_does_not_break_loading() {
+        let dir = TempDir::new();
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH - Duration::from_secs(1)));
+        let config = StorageConfig { clock, ttl_mode: TtlMode::Relative, ..dir.config() };
+        let mut store = KvStore::with_config(config.clone()).unwrap();
+        store.set_with_ttl("k".to_string(), "v".to_string(), Some(5)).unwrap();
+        drop(store);
+
+        assert_eq!(KvStore::with_config(config).unwrap().get_ttl("k"), Some(5));
+    }
+
+    #[test]
+    fn keys_lists_only_live_keys() {
+        let clock = manual_clock();
+        let mut store = KvStore::in_memory().with_clock(clock.clone());
+        set(&mut store, "a", "1");
+        store.set_with_ttl("b".to_string(), "2".to_string(), Some(1)).unwrap();
+        clock.advance(Duration::from_secs(2));
+
+        assert_eq!(store.keys(), vec!["a"]);
+    }
+
+    #[test]
+    fn max_ttl_caps_every_write() {
+        let mut store = KvStore::in_memory().with_max_ttl(10);
+        store.set_with_ttl("long".to_string(), "v".to_string(), Some(3600)).unwrap();
+        store.set_with_ttl("short".to_string(), "v".to_string(), Some(5)).unwrap();
+        set(&mut store, "forever", "v");
+
+        assert_eq!(store.get_ttl("long"), Some(10));
+        assert_eq!(store.get_ttl("short"), Some(5));
+        assert_eq!(store.get_ttl("forever"), Some(10));
+        assert!(store.persist("short").unwrap());
+        assert_eq!(store.get_ttl("short"), Some(10));
+    }
+
+    #[test]
+    fn bulk_load_counts_loaded_and_skipped_lines() {
+        let dir = TempDir::new();
+        let mut store = dir.open().with_max_key_bytes(8);
+        let tsv = "a\t1\r\nb\t2\tmore\n\nno tab\n\tempty key\nwaytoolongkey\tx\nc\t\n";
+
+        assert_eq!(store.bulk_load(tsv.as_bytes()).unwrap(), (3, 3));
+        assert_eq!(store.get("b").as_deref(), Some("2\tmore"));
+        assert_eq!(store.get("c").as_deref(), Some(""));
+        assert_eq!(store.write_bytes_total(), fs::metadata(dir.json_path()).unwrap().len());
+    }
+
+    #[test]
+    fn bulk_load_with_capacity_reserves_up_front() {
+        let mut store = KvStore::in_memory();
+        assert_eq!(store.bulk_load_with_capacity("a\t1\nb\t2".as_bytes(), 100).unwrap(), (2, 0));
+        assert!(store.store.capacity() >= 100);
+        assert_eq!(store.get("b").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn read_view_sees_the_store() {
+        let mut store = KvStore::in_memory();
+        store.set_with_ttl("k".to_string(), "v".to_string(), Some(30)).unwrap();
+        let view = store.read_view();
+        let copy = view;
+
+        assert_eq!(copy.get("k").as_deref(), Some("v"));
+        assert!(view.contains_key("k"));
+        assert_eq!(view.list(), vec![("k".to_string(), "v".to_string())]);
+        assert_eq!((view.len(), view.is_empty()), (1, false));
+        assert_eq!(view.get_ttl("k"), Some(30));
+    }
+
+    #[test]
+    fn self_test_passes_in_a_fresh_directory() {
+        let dir = TempDir::new();
+        KvStore::self_test(&dir.0.join("selftest")).unwrap();
+    }
+
+    #[test]
+    fn load_report_counts_expired_and_malformed_entries() {
+        let dir = TempDir::new();
+        fs::write(dir.json_path(), r#"{
+            "ok": {"data": "1", "expires_at_ms": null},
+            "old": {"data": "2", "expires_at_ms": 1},
+            "bad": {"nope": true},
+            "worse": 7
+        }"#).unwrap();
+
+        let store = KvStore::with_config(StorageConfig { salvage_malformed: true, ..dir.config() }).unwrap();
+        assert_eq!(store.load_report(), LoadReport { loaded: 2, expired: 1, malformed: 2 });
+        assert_eq!(store.list(), vec![("ok".to_string(), "1".to_string())]);
+    }
+
+    #[test]
+    fn malformed_entries_fail_the_open_unless_salvaging() {
+        let dir = TempDir::new();
+        let contents = r#"{"ok": {"data": "1", "expires_at_ms": null}, "bad": {"nope": true}}"#;
+        fs::write(dir.json_path(), contents).unwrap();
+
+        let err = KvStore::with_config(dir.config()).unwrap_err();
+        assert!(err.to_string().contains("malformed entry 'bad'"), "{}", err);
+        assert_eq!(fs::read_to_string(dir.json_path()).unwrap(), contents);
+    }
+
+    #[test]
+    fn reprefix_moves_keys_and_keeps_ttls() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "old:a", "1");
+        store.set_with_ttl("old:b".to_string(), "2".to_string(), Some(30)).unwrap();
+        set(&mut store, "new:a", "stale");
+        set(&mut store, "other", "3");
+
+        assert_eq!(store.reprefix("old:", "new:").unwrap(), 2);
+        let mut entries = store.list();
+        entries.sort();
+        assert_eq!(entries, vec![
+            ("new:a".to_string(), "1".to_string()),
+            ("new:b".to_string(), "2".to_string()),
+            ("other".to_string(), "3".to_string()),
+        ]);
+        assert_eq!(store.get_ttl("new:b"), Some(30));
+    }
+
+    #[test]
+    fn sorted_snapshots_are_byte_stable() {
+        let write = |order: &[&str], clock: Arc<ManualClock>| {
+            let mut store = KvStore::in_memory().with_clock(clock).with_sorted_keys(true);
+            for key in order {
+                set(&mut store, key, "v");
+            }
+            let mut bytes = Vec::new();
+            store.write_snapshot(&mut bytes).unwrap();
+            bytes
+        };
+
+        let later = manual_clock();
+        later.advance(Duration::from_secs(5));
+        let first = write(&["a", "b", "c"], manual_clock());
+        let second = write(&["c", "a", "b"], later);
+        assert_eq!(first, second);
+        let text = String::from_utf8(first).unwrap();
+        assert!(text.starts_with(r#"{"a":"#));
+        assert!(text.contains(r#""seq":0"#) && !text.contains(r#""seq":1"#));
+    }
+
+    #[test]
+    fn opening_keeps_sorted_relative_files_byte_stable() {
+        let clock = manual_clock();
+        let config = |dir: &TempDir| StorageConfig { clock: clock.clone(), ttl_mode: TtlMode::Relative, sorted_keys: true, ..dir.config() };
+        let (dir, expected_dir) = (TempDir::new(), TempDir::new());
+        let mut store = KvStore::with_config(config(&dir)).unwrap();
+        store.set_with_ttl("gone".to_string(), "x".to_string(), Some(1)).unwrap();
+        clock.advance(Duration::from_secs(2));
+        // Saved while `gone` is expired but not yet dropped
+        for key in ["b", "a"] {
+            set(&mut store, key, "v");
+        }
+        drop(store);
+        let mut expected = KvStore::with_config(config(&expected_dir)).unwrap();
+        for key in ["a", "b"] {
+            set(&mut expected, key, "v");
+        }
+        let expected = fs::read(expected_dir.json_path()).unwrap();
+
+        let reopened = KvStore::with_config(config(&dir)).unwrap();
+        assert_eq!(reopened.load_report().expired, 1);
+        assert_eq!(fs::read(dir.json_path()).unwrap(), expected);
+        drop(reopened);
+        KvStore::with_config(config(&dir)).unwrap();
+        assert_eq!(fs::read(dir.json_path()).unwrap(), expected);
+    }
+
+    #[test]
+    fn filter_by_type_matches_parsed_json() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "n2", "2");
+        set(&mut store, "n1", "1.5");
+        set(&mut store, "text", "hello");
+        set(&mut store, "quoted", "\"hi\"");
+        set(&mut store, "obj", "{}");
+
+        assert_eq!(store.filter_by_type(ValueType::Number), vec![
+            ("n1".to_string(), serde_json::json!(1.5)),
+            ("n2".to_string(), serde_json::json!(2)),
+        ]);
+        let strings: Vec<String> = store.filter_by_type(ValueType::String).into_iter().map(|(key, _)| key).collect();
+        assert_eq!(strings, vec!["quoted", "text"]);
+        assert!(store.filter_by_type(ValueType::Bool).is_empty());
+    }
+
+    #[test]
+    fn serialized_size_matches_the_saved_file() {
+        let dir = TempDir::new();
+        let mut store = dir.open();
+        set(&mut store, "a", "1");
+        store.set_with_ttl("b".to_string(), "two".to_string(), Some(60)).unwrap();
+
+        let size = store.serialized_size().unwrap();
+        assert_eq!(size as u64, fs::metadata(dir.json_path()).unwrap().len());
+        let binary = store.with_format(StorageFormat::Bincode);
+        assert!(binary.serialized_size().unwrap() != size);
+    }
+
+    #[test]
+    fn opening_saves_only_when_something_expired() {
+        let dir = TempDir::new();
+        let clock = manual_clock();
+        let config = StorageConfig { clock: clock.clone(), ..dir.config() };
+        let mut store = KvStore::with_config(config.clone()).unwrap();
+        set(&mut store, "keep", "1");
+        store.set_with_ttl("a".to_string(), "2".to_string(), Some(1)).unwrap();
+        store.set_with_ttl("b".to_string(), "3".to_string(), Some(1)).unwrap();
+        drop(store);
+
+        let reopened = KvStore::with_config(config.clone()).unwrap();
+        assert_eq!(reopened.write_bytes_total(), 0);
+        drop(reopened);
+
+        clock.advance(Duration::from_secs(2));
+        let mut expired = KvStore::with_config(config).unwrap();
+        assert_eq!(expired.write_bytes_total(), fs::metadata(dir.json_path()).unwrap().len());
+        assert_eq!(expired.cleanup_expired().unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn sliding_expiry_is_extended_by_reads() {
+        let clock = manual_clock();
+        let mut store = KvStore::in_memory().with_clock(clock.clone());
+        store.set_with_policy("slide".to_string(), "v".to_string(), ExpiryPolicy::Sliding(10)).unwrap();
+        store.set_with_policy("fixed".to_string(), "v".to_string(), ExpiryPolicy::Fixed(10)).unwrap();
+        store.set_with_policy("never".to_string(), "v".to_string(), ExpiryPolicy::Never).unwrap();
+
+        clock.advance(Duration::from_secs(8));
+        assert!(store.get("slide").is_some());
+        assert!(store.get("fixed").is_some());
+        clock.advance(Duration::from_secs(8));
+        assert_eq!(store.get_ttl("slide"), Some(2));
+        assert_eq!(store.get("fixed"), None);
+        assert_eq!(store.get_ttl("never"), None);
+        assert!(store.contains_key("never"));
+    }
+
+    #[test]
+    fn rewriting_a_sliding_key_keeps_it_sliding() {
+        let clock = manual_clock();
+        let mut store = KvStore::in_memory().with_clock(clock.clone());
+        let keys = ["truncated", "patched", "appended", "kept", "counted"];
+        for (key, value) in keys.iter().zip(["abcdef", "{}", "[]", "old", "1"]) {
+            store.set_with_policy(key.to_string(), value.to_string(), ExpiryPolicy::Sliding(10)).unwrap();
+        }
+        clock.advance(Duration::from_secs(5));
+        for key in keys {
+            store.get(key);
+        }
+
+        store.truncate_value("truncated", 3).unwrap();
+        store.merge_patch("patched", serde_json::json!({ "a": 1 })).unwrap();
+        store.append_capped("appended", serde_json::json!(1), 5).unwrap();
+        store.set_keep_ttl("kept".to_string(), "new".to_string()).unwrap();
+        store.increment("counted", 1).unwrap();
+
+        clock.advance(Duration::from_secs(8));
+        for key in keys {
+            assert_eq!(store.store[key].sliding_ttl, Some(10), "{}", key);
+            assert_eq!(store.get_ttl(key), Some(2), "{}", key);
+        }
+    }
+
+    #[test]
+    fn set_keep_ttl_treats_absent_keys_like_set() {
+        let mut store = KvStore::in_memory();
+        store.set_keep_ttl("k".to_string(), "v".to_string()).unwrap();
+        assert_eq!(store.get("k").as_deref(), Some("v"));
+        assert_eq!(store.get_ttl("k"), None);
+    }
+
+    #[test]
+    fn flatten_expands_nested_objects() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "config", r#"{"db":{"host":"h","port":5432},"tags":["a"],"empty":{}}"#);
+        set(&mut store, "plain", "text");
+
+        let flat = store.flatten();
+        let expected: BTreeMap<String, serde_json::Value> = [
+            ("config.db.host", serde_json::json!("h")),
+            ("config.db.port", serde_json::json!(5432)),
+            ("config.empty", serde_json::json!({})),
+            ("config.tags", serde_json::json!(["a"])),
+            ("plain", serde_json::json!("text")),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect();
+        assert_eq!(flat, expected);
+    }
+
+    #[test]
+    fn load_env_matches_the_prefix_on_a_boundary() {
+        std::env::set_var("KVTESTLOAD_DB_HOST", "localhost");
+        std::env::set_var("KVTESTLOAD_PORT", "5432");
+        std::env::set_var("KVTESTLOADER_OTHER", "no");
+        std::env::set_var("KVTESTLOAD_", "no");
+
+        for prefix in ["KVTESTLOAD", "KVTESTLOAD_"] {
+            let mut store = KvStore::in_memory();
+            assert_eq!(store.load_env(prefix).unwrap(), 2);
+            let mut entries = store.list();
+            entries.sort();
+            assert_eq!(entries, vec![
+                ("db_host".to_string(), "localhost".to_string()),
+                ("port".to_string(), "5432".to_string()),
+            ]);
+        }
+    }
+
+    #[test]
+    fn load_env_checks_key_length_before_loading() {
+        std::env::set_var("KVTESTLEN_SHORT", "1");
+        std::env::set_var("KVTESTLEN_MUCH_TOO_LONG", "2");
+
+        let mut store = KvStore::in_memory().with_max_key_bytes(5);
+        let error = store.load_env("KVTESTLEN").unwrap_err();
+        assert!(error.downcast_ref::<KeyTooLong>().is_some());
+        assert!(store.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn load_env_skips_values_that_are_not_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+        std::env::set_var("KVTESTUTF_GOOD", "ok");
+        std::env::set_var("KVTESTUTF_BAD", std::ffi::OsString::from_vec(vec![0xff, 0xfe]));
+
+        let mut store = KvStore::in_memory();
+        assert_eq!(store.load_env("KVTESTUTF").unwrap(), 1);
+        assert_eq!(store.get("good").as_deref(), Some("ok"));
+    }
+
+    #[test]
+    fn write_bytes_total_adds_up_every_save() {
+        let dir = TempDir::new();
+        let mut store = dir.open();
+        assert_eq!(store.write_bytes_total(), 0);
+
+        let mut total = 0;
+        for value in ["1", "22", "333"] {
+            set(&mut store, "k", value);
+            total += fs::metadata(dir.json_path()).unwrap().len();
+        }
+        assert_eq!(store.write_bytes_total(), total);
+        assert_eq!(KvStore::in_memory().write_bytes_total(), 0);
+    }
+
+    #[test]
+    fn detailed_list_is_sorted_with_types_and_ttls() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "b", "[1]");
+        store.set_with_ttl("a".to_string(), "true".to_string(), Some(20)).unwrap();
+
+        assert_eq!(store.detailed_list(), vec![
+            EntryDetail { key: "a".to_string(), value: serde_json::json!(true), value_type: ValueType::Bool, ttl: Some(20) },
+            EntryDetail { key: "b".to_string(), value: serde_json::json!([1]), value_type: ValueType::Array, ttl: None },
+        ]);
+    }
+
+    #[test]
+    fn retain_removes_rejected_entries_and_saves_once() {
+        let dir = TempDir::new();
+        let mut store = dir.open();
+        store.set_many(vec![
+            ("keep:1".to_string(), "a".to_string(), None),
+            ("drop:1".to_string(), "b".to_string(), None),
+            ("drop:2".to_string(), "c".to_string(), None),
+        ]).unwrap();
+        let before = store.write_bytes_total();
+
+        assert_eq!(store.retain(|key, _| key.starts_with("keep:")).unwrap(), 2);
+        assert_eq!(store.keys(), vec!["keep:1"]);
+        assert_eq!(store.write_bytes_total() - before, fs::metadata(dir.json_path()).unwrap().len());
+        assert_eq!(store.retain(|_, _| true).unwrap(), 0);
+    }
+
+    #[test]
+    fn numeric_summary_skips_non_numeric_values() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "m:a", "1");
+        set(&mut store, "m:b", " 2.5 ");
+        set(&mut store, "m:c", "-0.5");
+        set(&mut store, "m:text", "abc");
+        set(&mut store, "m:inf", "inf");
+        set(&mut store, "other", "100");
+
+        assert_eq!(store.numeric_summary("m:"), Some(NumericSummary { count: 3, sum: 3.0, min: -0.5, max: 2.5, mean: 1.0 }));
+        assert_eq!(store.numeric_summary("none:"), None);
+    }
+
+    #[test]
+    fn saves_stream_the_snapshot_to_the_file() {
+        let dir = TempDir::new();
+        let mut store = dir.open().with_format(StorageFormat::Bincode).with_sorted_keys(true);
+        set(&mut store, "a", &"x".repeat(10_000));
+        set(&mut store, "b", "2");
+
+        let mut streamed = CountingWriter::new(Vec::new());
+        store.write_snapshot(&mut streamed).unwrap();
+        assert_eq!(streamed.written, streamed.inner.len());
+        assert!(fs::read(dir.json_path()).unwrap() == streamed.inner);
+        assert!(fs::read_dir(&dir.0).unwrap().all(|entry| !entry.unwrap().file_name().to_string_lossy().ends_with(".tmp")));
+    }
+
+    #[test]
+    fn get_first_returns_the_first_live_key() {
+        let clock = manual_clock();
+        let mut store = KvStore::in_memory().with_clock(clock.clone());
+        store.set_with_ttl("expired".to_string(), "old".to_string(), Some(1)).unwrap();
+        set(&mut store, "fallback", "f");
+        set(&mut store, "last", "l");
+        clock.advance(Duration::from_secs(2));
+
+        assert_eq!(store.get_first(&["missing", "expired", "fallback", "last"]), Some(("fallback".to_string(), "f".to_string())));
+        assert_eq!(store.get_first(&["missing"]), None);
+    }
+
+    #[test]
+    fn export_subset_writes_only_live_requested_keys() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "b", "2");
+        set(&mut store, "a", "1");
+        set(&mut store, "c", "3");
+
+        let mut out = Vec::new();
+        assert_eq!(store.export_subset(&["b", "a", "missing"], &mut out).unwrap(), 2);
+        assert_eq!(String::from_utf8(out).unwrap(), r#"{"a":"1","b":"2"}"#);
+    }
+
+    #[test]
+    fn prime_from_copies_matching_keys_without_overwriting() {
+        let mut source = KvStore::in_memory();
+        set(&mut source, "user:1", "alice");
+        source.set_with_ttl("user:2".to_string(), "bob".to_string(), Some(30)).unwrap();
+        set(&mut source, "user:3", "carol");
+        set(&mut source, "session:1", "s");
+
+        let mut store = KvStore::in_memory();
+        set(&mut store, "user:3", "local");
+        assert_eq!(store.prime_from(&source, |key| key.starts_with("user:")).unwrap(), 2);
+        assert_eq!(store.get("user:1").as_deref(), Some("alice"));
+        assert_eq!(store.get_ttl("user:2"), Some(30));
+        assert_eq!(store.get("user:3").as_deref(), Some("local"));
+        assert!(!store.contains_key("session:1"));
+    }
+
+    #[test]
+    fn top_prefixes_counts_keys_per_prefix() {
+        let mut store = KvStore::in_memory();
+        for key in ["user:1", "user:2", "user:3", "post:1", "post:2", "tag:1", "bare"] {
+            set(&mut store, key, "v");
+        }
+
+        assert_eq!(store.top_prefixes(':', 3), vec![
+            ("user".to_string(), 3),
+            ("post".to_string(), 2),
+            ("bare".to_string(), 1),
+        ]);
+    }
+
+    #[test]
+    fn counter_buffering_defers_increment_saves() {
+        let dir = TempDir::new();
+        let mut store = dir.open().with_counter_buffering(Duration::from_secs(3600));
+        store.increment("hits", 1).unwrap();
+        let after_first = store.write_bytes_total();
+        assert!(after_first > 0);
+
+        for _ in 0..10 {
+            store.increment("hits", 1).unwrap();
+        }
+        assert_eq!(store.write_bytes_total(), after_first);
+        assert_eq!(store.sync_status(), SyncStatus::Pending);
+
+        store.flush().unwrap();
+        assert_eq!(store.sync_status(), SyncStatus::Synced);
+        drop(store);
+        assert_eq!(dir.open().get("hits").as_deref(), Some("11"));
+    }
+
+    #[test]
+    fn infer_schema_describes_each_key() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "name", "plain text");
+        set(&mut store, "count", "3");
+        set(&mut store, "tags", r#"["a","b"]"#);
+        set(&mut store, "mixed", r#"[1,"a",1]"#);
+        set(&mut store, "user", r#"{"id":1,"admin":false}"#);
+
+        assert_eq!(store.infer_schema(), serde_json::json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "count": { "type": "number" },
+                "tags": { "type": "array", "items": { "type": "string" } },
+                "mixed": { "type": "array", "items": { "type": ["number", "string"] } },
+                "user": { "type": "object", "properties": {
+                    "id": { "type": "number" },
+                    "admin": { "type": "boolean" },
+                } },
+            }
+        }));
+    }
+
+    #[test]
+    fn evict_to_bytes_drops_expiring_then_oldest_keys() {
+        let clock = manual_clock();
+        let mut store = KvStore::in_memory().with_clock(clock.clone());
+        store.set_with_ttl("stale".to_string(), "xxxx".to_string(), Some(1)).unwrap();
+        set(&mut store, "old", "aaaa");
+        store.set_with_ttl("soon".to_string(), "bbbb".to_string(), Some(60)).unwrap();
+        set(&mut store, "new", "cccc");
+        clock.advance(Duration::from_secs(2));
+
+        // Three live entries of 7 or 8 bytes each; the expired one goes for free
+        assert_eq!(store.evict_to_bytes(16).unwrap(), 1);
+        assert_eq!(sorted_keys(&store), vec!["new", "old"]);
+        assert_eq!(store.evict_to_bytes(7).unwrap(), 1);
+        assert_eq!(sorted_keys(&store), vec!["new"]);
+        assert_eq!(store.evict_to_bytes(100).unwrap(), 0);
+    }
+
+    #[test]
+    fn get_entry_returns_value_with_metadata() {
+        let clock = manual_clock();
+        let mut store = KvStore::in_memory().with_clock(clock.clone());
+        set(&mut store, "first", "plain");
+        store.set_with_ttl("second".to_string(), r#"{"a":1}"#.to_string(), Some(60)).unwrap();
+        clock.advance(Duration::from_millis(10_500));
+
+        let first = store.get_entry("first").unwrap();
+        let second = store.get_entry("second").unwrap();
+        assert_eq!(first.value, "plain");
+        assert_eq!(first.ttl, None);
+        assert_eq!(first.value_type, ValueType::String);
+        assert_eq!(second.value_type, ValueType::Object);
+        // Partial seconds round up
+        assert_eq!(second.ttl, Some(50));
+        assert!(second.seq > first.seq);
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(store.get_entry("second"), None);
+        assert_eq!(store.get_entry("missing"), None);
+    }
+
+    #[test]
+    fn unsynced_writes_survive_a_failing_disk() {
+        let dir = TempDir::new();
+        let mut store = dir.open().with_unsynced_writes(true);
+        set(&mut store, "a", "1");
+        assert_eq!(store.sync_status(), SyncStatus::Synced);
+
+        // Saves fail while the directory is gone
+        fs::remove_dir_all(&dir.0).unwrap();
+        set(&mut store, "b", "2");
+        assert_eq!(store.get("b").as_deref(), Some("2"));
+        assert!(matches!(store.sync_status(), SyncStatus::Unsynced(_)));
+        assert!(store.flush().is_err());
+
+        fs::create_dir_all(&dir.0).unwrap();
+        store.flush().unwrap();
+        assert_eq!(store.sync_status(), SyncStatus::Synced);
+        drop(store);
+        assert_eq!(dir.open().get("b").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn save_failures_are_returned_by_default() {
+        let dir = TempDir::new();
+        let mut store = dir.open();
+        fs::remove_dir_all(&dir.0).unwrap();
+        assert!(store.set("a".to_string(), "1".to_string()).is_err());
+        assert!(matches!(store.sync_status(), SyncStatus::Unsynced(_)));
+    }
+
+    #[test]
+    fn set_derived_combines_current_sources() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "first", "Ada");
+        set(&mut store, "last", "Lovelace");
+        let full_name = |sources: &[Option<String>]| {
+            sources.iter().map(|source| source.as_deref().unwrap_or("?")).collect::<Vec<_>>().join(" ")
+        };
+
+        store.set_derived("full", &["first", "last"], full_name).unwrap();
+        assert_eq!(store.get("full").as_deref(), Some("Ada Lovelace"));
+
+        store.delete("last").unwrap();
+        store.set_derived("full", &["first", "last"], full_name).unwrap();
+        assert_eq!(store.get("full").as_deref(), Some("Ada ?"));
+    }
+
+    #[test]
+    fn classify_values_splits_json_from_plain_text() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "obj", r#"{"a":1}"#);
+        set(&mut store, "num", "42");
+        set(&mut store, "word", "hello");
+        set(&mut store, "broken", "{not json");
+
+        let (json, plain) = store.classify_values();
+        assert_eq!(json, vec!["num", "obj"]);
+        assert_eq!(plain, vec!["broken", "word"]);
+    }
+
+    #[test]
+    fn rename_many_can_swap_keys() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "a", "1");
+        set(&mut store, "b", "2");
+
+        assert_eq!(store.rename_many(&[("a", "b"), ("b", "a"), ("missing", "c")]).unwrap(), 2);
+        assert_eq!(store.get("a").as_deref(), Some("2"));
+        assert_eq!(store.get("b").as_deref(), Some("1"));
+        assert!(!store.contains_key("c"));
+    }
+
+    #[test]
+    fn rename_many_rejects_conflicts_without_changes() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "a", "1");
+        set(&mut store, "b", "2");
+        set(&mut store, "c", "3");
+
+        assert!(store.rename_many(&[("a", "x"), ("b", "x")]).is_err());
+        assert!(store.rename_many(&[("a", "c")]).is_err());
+        assert_eq!(sorted_keys(&store), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn key_checksums_change_only_for_changed_values() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "a", "1");
+        set(&mut store, "b", "2");
+        let before = store.key_checksums();
+        assert_eq!(before["a"], crc32fast::hash(b"1"));
+
+        set(&mut store, "b", "changed");
+        let after = store.key_checksums();
+        assert_eq!(after["a"], before["a"]);
+        assert_ne!(after["b"], before["b"]);
+        assert_eq!(after.len(), 2);
+    }
+
+    #[test]
+    fn without_persistence_saves_once() {
+        let dir = TempDir::new();
+        let mut store = dir.open();
+        store.without_persistence(|store| {
+            for i in 0..10 {
+                set(store, &format!("k{}", i), "v");
+            }
+            store.delete("k0").unwrap();
+        }).unwrap();
+
+        // One snapshot written, the size of the final file
+        assert_eq!(store.write_bytes_total(), fs::metadata(dir.json_path()).unwrap().len());
+        drop(store);
+        assert_eq!(dir.open().len(), 9);
+    }
+
+    #[test]
+    fn reload_merging_keeps_local_changes() {
+        let dir = TempDir::new();
+        let mut local = dir.open().with_autosave(false);
+        set(&mut local, "shared", "local");
+
+        let mut other = dir.open();
+        set(&mut other, "shared", "disk");
+        set(&mut other, "external", "x");
+
+        assert_eq!(local.reload_merging(MergeStrategy::KeepExisting).unwrap(), 1);
+        assert_eq!(local.get("shared").as_deref(), Some("local"));
+        assert_eq!(local.get("external").as_deref(), Some("x"));
+
+        assert_eq!(local.reload_merging(MergeStrategy::PreferDisk).unwrap(), 2);
+        assert_eq!(local.get("shared").as_deref(), Some("disk"));
+    }
+
+    #[test]
+    fn reload_merging_does_not_revive_deleted_keys() {
+        let dir = TempDir::new();
+        let clock = manual_clock();
+        let config = StorageConfig { clock: clock.clone(), ..dir.config() };
+        let mut other = KvStore::with_config(config.clone()).unwrap();
+        set(&mut other, "gone", "old");
+        set(&mut other, "rewritten", "old");
+
+        let mut local = KvStore::with_config(config).unwrap().with_autosave(false);
+        clock.advance(Duration::from_secs(1));
+        local.delete("gone").unwrap();
+        local.delete("rewritten").unwrap();
+        clock.advance(Duration::from_secs(1));
+        set(&mut other, "rewritten", "new");
+
+        assert_eq!(local.reload_merging(MergeStrategy::KeepExisting).unwrap(), 0);
+        assert_eq!(local.len(), 0);
+        // Only a write made after the deletion wins, and only when preferred
+        assert_eq!(local.reload_merging(MergeStrategy::PreferDisk).unwrap(), 1);
+        assert!(!local.contains_key("gone"));
+        assert_eq!(local.get("rewritten").as_deref(), Some("new"));
+    }
+
+    #[test]
+    fn changed_against_reports_added_and_changed_keys() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "same", "1");
+        set(&mut store, "changed", "new");
+        set(&mut store, "added", "x");
+        let baseline: HashMap<String, String> = [("same", "1"), ("changed", "old"), ("removed", "y")]
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        assert_eq!(store.changed_against(&baseline), vec!["added", "changed"]);
+    }
+
+    #[test]
+    fn saves_leave_no_temp_files_behind() {
+        let dir = TempDir::new();
+        let mut store = dir.open();
+        for i in 0..5 {
+            set(&mut store, &format!("k{}", i), "v");
+        }
+        let names: Vec<_> = fs::read_dir(&dir.0).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(names, vec!["kv_store.json"]);
+    }
+
+    #[test]
+    fn interrupted_temp_write_leaves_the_store_intact() {
+        let dir = TempDir::new();
+        let mut store = dir.open();
+        set(&mut store, "a", "1");
+        drop(store);
+        // What a save killed before its rename leaves behind
+        fs::write(dir.0.join("kv_store.json.999-0.tmp"), r#"{"store":{"a":{"da"#).unwrap();
+
+        let reopened = dir.open();
+        assert_eq!(reopened.get("a").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn get_or_assign_id_keeps_the_first_id() {
+        let mut store = KvStore::in_memory();
+        let first = store.get_or_assign_id_with("id", || "id-1".to_string()).unwrap();
+        let second = store.get_or_assign_id_with("id", || "id-2".to_string()).unwrap();
+        assert_eq!(first, "id-1");
+        assert_eq!(second, "id-1");
+
+        let random = store.get_or_assign_id("other").unwrap();
+        assert!(uuid::Uuid::parse_str(&random).is_ok());
+        assert_eq!(store.get_or_assign_id("other").unwrap(), random);
+    }
+
+    #[test]
+    fn corrupt_file_is_an_error_and_left_alone() {
+        let dir = TempDir::new();
+        fs::write(dir.json_path(), "{ not json").unwrap();
+
+        assert!(KvStore::with_config(dir.config()).is_err());
+        assert_eq!(fs::read_to_string(dir.json_path()).unwrap(), "{ not json");
+    }
+
+    #[test]
+    fn recovering_moves_a_corrupt_file_aside() {
+        let dir = TempDir::new();
+        fs::write(dir.json_path(), "{ not json").unwrap();
+        let config = StorageConfig { clock: Arc::new(ManualClock::at_millis(1234)), ..dir.config() };
+
+        let store = KvStore::open_recovering(config).unwrap();
+        assert!(store.is_empty());
+        assert_eq!(fs::read_to_string(dir.0.join("kv_store.json.corrupt.1234")).unwrap(), "{ not json");
+        assert!(!dir.json_path().exists());
+    }
+
+    #[test]
+    fn stores_in_different_directories_are_independent() {
+        let (first_dir, second_dir) = (TempDir::new(), TempDir::new());
+        let mut first = first_dir.open();
+        let mut second = second_dir.open();
+        set(&mut first, "k", "first");
+        set(&mut second, "k", "second");
+        drop((first, second));
+
+        assert_eq!(first_dir.open().get("k").as_deref(), Some("first"));
+        assert_eq!(second_dir.open().get("k").as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn with_config_creates_nested_directories() {
+        let dir = TempDir::new();
+        let config = StorageConfig { directory: dir.0.join("a").join("b"), ..dir.config() };
+        let mut store = KvStore::with_config(config).unwrap();
+        set(&mut store, "k", "v");
+        assert!(dir.0.join("a").join("b").join("kv_store.json").exists());
+    }
+
+    #[test]
+    fn prometheus_metrics_report_the_workload() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "a", "1");
+        set(&mut store, "b", "2");
+        store.set_with_ttl("c".to_string(), "3".to_string(), Some(60)).unwrap();
+        store.delete("a").unwrap();
+
+        let metrics = store.prometheus_metrics();
+        for line in ["kvstore_keys_total 2", "kvstore_keys_with_ttl 1", "kvstore_keys_peak 3", "kvstore_unsynced 0"] {
+            assert!(metrics.lines().any(|l| l == line), "missing {:?} in:\n{}", line, metrics);
+        }
+        assert!(metrics.contains("# TYPE kvstore_write_bytes_total counter\n"));
+    }
+
+    #[test]
+    fn follower_converges_on_the_change_feed() {
+        let mut primary = KvStore::in_memory();
+        set(&mut primary, "existing", "1");
+        let feed = primary.change_feed();
+        let mut follower = KvStore::in_memory();
+
+        set(&mut primary, "a", "1");
+        set(&mut primary, "b", "2");
+        primary.expire("a", 60).unwrap();
+        set(&mut primary, "b", "3");
+        primary.delete("existing").unwrap();
+        for change in feed.try_iter() {
+            follower.apply_change(change).unwrap();
+        }
+
+        let mut expected = primary.list();
+        let mut replicated = follower.list();
+        expected.sort();
+        replicated.sort();
+        assert_eq!(replicated, expected);
+        assert_eq!(follower.get_ttl("a"), Some(60));
+    }
+
+    #[test]
+    fn changes_serialize_for_sending() {
+        let change = Change::Set { key: "k".to_string(), value: "v".to_string(), expires_at: Some(5) };
+        let json = serde_json::to_string(&change).unwrap();
+        assert_eq!(serde_json::from_str::<Change>(&json).unwrap(), change);
+    }
+
+    #[test]
+    fn every_write_returns_save_errors() {
+        let dir = TempDir::new();
+        let mut store = dir.open();
+        set(&mut store, "a", "1");
+        fs::remove_dir_all(&dir.0).unwrap();
+
+        assert!(store.delete("a").is_err());
+        assert!(store.set_many(vec![("b".to_string(), "2".to_string(), None)]).is_err());
+        assert!(store.cleanup_expired().is_ok_and(|expired| expired.is_empty()));
+    }
+
+    #[test]
+    fn verify_file_reports_without_opening() {
+        let dir = TempDir::new();
+        let mut store = dir.open();
+        set(&mut store, "a", "1");
+        set(&mut store, "b", "2");
+        drop(store);
+        assert_eq!(KvStore::verify_file(dir.json_path()).unwrap(), VerifyReport {
+            well_formed: true,
+            entries: 2,
+            malformed: Vec::new(),
+        });
+
+        let bad_entries = dir.0.join("entries.json");
+        fs::write(&bad_entries, r#"{"good":{"data":"x","expires_at":null},"bad":{"nope":1},"worse":3}"#).unwrap();
+        let report = KvStore::verify_file(&bad_entries).unwrap();
+        assert!(report.well_formed);
+        assert_eq!(report.entries, 3);
+        assert_eq!(report.malformed, vec!["bad", "worse"]);
+
+        let truncated = dir.0.join("truncated.json");
+        fs::write(&truncated, r#"{"good":{"da"#).unwrap();
+        assert_eq!(KvStore::verify_file(&truncated).unwrap(), VerifyReport::default());
+        assert!(KvStore::verify_file(dir.0.join("missing.json")).is_err());
+    }
+
+    #[test]
+    fn batch_writes_save_once() {
+        let dir = TempDir::new();
+        let mut store = dir.open();
+        let entries = (0..100).map(|i| (format!("k{}", i), i.to_string(), None)).collect();
+        store.set_many(entries).unwrap();
+        assert_eq!(store.write_bytes_total(), fs::metadata(dir.json_path()).unwrap().len());
+
+        assert_eq!(store.get_many(&["k1", "nope", "k99"]), vec![Some("1".to_string()), None, Some("99".to_string())]);
+
+        let before = store.write_bytes_total();
+        let removed = store.delete_many(&["k1", "nope", "k2"]).unwrap();
+        assert_eq!(removed, vec![Some("1".to_string()), None, Some("2".to_string())]);
+        assert_eq!(store.write_bytes_total() - before, fs::metadata(dir.json_path()).unwrap().len());
+        assert_eq!(store.len(), 98);
+    }
+
+    #[test]
+    fn mget_object_maps_keys_to_json_or_null() {
+        let clock = manual_clock();
+        let mut store = KvStore::in_memory().with_clock(clock.clone());
+        set(&mut store, "user", r#"{"name":"ada"}"#);
+        set(&mut store, "word", "hello");
+        store.set_with_ttl("gone".to_string(), "x".to_string(), Some(1)).unwrap();
+        clock.advance(Duration::from_secs(1));
+
+        assert_eq!(store.mget_object(&["user", "word", "gone", "missing"]), serde_json::json!({
+            "user": { "name": "ada" },
+            "word": "hello",
+            "gone": null,
+            "missing": null,
+        }));
+    }
+
+    #[test]
+    fn autosave_off_writes_only_on_flush() {
+        let dir = TempDir::new();
+        let mut store = dir.open().with_autosave(false);
+        set(&mut store, "a", "1");
+        store.delete("a").unwrap();
+        set(&mut store, "b", "2");
+        assert!(!dir.json_path().exists());
+        assert_eq!(store.sync_status(), SyncStatus::Pending);
+
+        store.flush().unwrap();
+        assert_eq!(store.sync_status(), SyncStatus::Synced);
+        assert_eq!(dir.open().get("b").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn drop_writes_pending_changes() {
+        let dir = TempDir::new();
+        let mut store = dir.open().with_autosave(false);
+        set(&mut store, "a", "1");
+        drop(store);
+        assert_eq!(dir.open().get("a").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn truncate_value_keeps_the_chosen_end_and_ttl() {
+        let mut store = KvStore::in_memory();
+        store.set_with_ttl("log".to_string(), "héllo world".to_string(), Some(60)).unwrap();
+        set(&mut store, "short", "abc");
+
+        assert!(store.truncate_value("log", 5).unwrap());
+        assert_eq!(store.get("log").as_deref(), Some("héllo"));
+        assert_eq!(store.get_ttl("log"), Some(60));
+        assert!(store.truncate_value_keeping("log", 3, KeepEnd::Tail).unwrap());
+        assert_eq!(store.get("log").as_deref(), Some("llo"));
+
+        assert!(!store.truncate_value("short", 5).unwrap());
+        assert!(!store.truncate_value("missing", 5).unwrap());
+        assert_eq!(store.get("short").as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn compare_and_swap_only_swaps_on_a_match() {
+        let dir = TempDir::new();
+        let mut store = dir.open();
+        assert!(store.compare_and_swap("lock", None, "owner-1".to_string()).unwrap());
+        assert!(!store.compare_and_swap("lock", None, "owner-2".to_string()).unwrap());
+        let written = store.write_bytes_total();
+        assert!(!store.compare_and_swap("lock", Some("owner-2"), "owner-3".to_string()).unwrap());
+        assert_eq!(store.write_bytes_total(), written);
+
+        assert!(store.compare_and_swap("lock", Some("owner-1"), "owner-2".to_string()).unwrap());
+        assert_eq!(store.get("lock").as_deref(), Some("owner-2"));
+    }
+
+    #[test]
+    fn compare_and_swap_treats_expired_keys_as_absent() {
+        let clock = manual_clock();
+        let mut store = KvStore::in_memory().with_clock(clock.clone());
+        store.set_with_ttl("lock".to_string(), "stale".to_string(), Some(1)).unwrap();
+        clock.advance(Duration::from_secs(1));
+
+        assert!(!store.compare_and_swap("lock", Some("stale"), "x".to_string()).unwrap());
+        assert!(store.compare_and_swap("lock", None, "fresh".to_string()).unwrap());
+    }
+
+    #[test]
+    fn keys_with_value_finds_exact_matches() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "b", "target");
+        set(&mut store, "a", "target");
+        set(&mut store, "c", "target ");
+
+        assert_eq!(store.keys_with_value("target"), vec!["a", "b"]);
+        assert!(store.keys_with_value("other").is_empty());
+    }
+
+    #[test]
+    fn increment_and_decrement_integers() {
+        let mut store = KvStore::in_memory().with_history(5);
+        assert_eq!(store.increment("fresh", 5).unwrap(), 5);
+        assert_eq!(store.decrement("fresh", 7).unwrap(), -2);
+        assert_eq!(store.decrement("other", 1).unwrap(), -1);
+        assert_eq!(store.history("fresh"), vec!["5"]);
+
+        store.set_with_ttl("counter".to_string(), "10".to_string(), Some(60)).unwrap();
+        assert_eq!(store.increment("counter", 1).unwrap(), 11);
+        assert_eq!(store.get_ttl("counter"), Some(60));
+
+        set(&mut store, "word", "abc");
+        assert!(store.increment("word", 1).is_err());
+        assert_eq!(store.get("word").as_deref(), Some("abc"));
+        set(&mut store, "max", &i64::MAX.to_string());
+        assert!(store.increment("max", 1).is_err());
+        assert!(store.decrement("fresh", i64::MIN).is_err());
+    }
+
+    #[test]
+    fn prune_history_keeps_the_newest_versions() {
+        let mut store = KvStore::in_memory().with_history(10);
+        for i in 1..=5 {
+            set(&mut store, "a", &i.to_string());
+        }
+        set(&mut store, "b", "1");
+        set(&mut store, "b", "2");
+
+        assert_eq!(store.prune_history(2).unwrap(), 2);
+        assert_eq!(store.history("a"), vec!["3", "4"]);
+        assert_eq!(store.history("b"), vec!["1"]);
+        assert_eq!(store.prune_history(2).unwrap(), 0);
+        assert_eq!(store.get("a").as_deref(), Some("5"));
+    }
+
+    #[test]
+    fn prefix_listing_skips_other_and_expired_keys() {
+        let clock = manual_clock();
+        let mut store = KvStore::in_memory().with_clock(clock.clone());
+        set(&mut store, "user:1", "ada");
+        set(&mut store, "user:2", "bob");
+        set(&mut store, "post:1", "hi");
+        store.set_with_ttl("user:3".to_string(), "gone".to_string(), Some(1)).unwrap();
+        clock.advance(Duration::from_secs(1));
+
+        let mut users = store.list_prefix("user:");
+        users.sort();
+        assert_eq!(users, vec![("user:1".to_string(), "ada".to_string()), ("user:2".to_string(), "bob".to_string())]);
+        let mut keys = store.keys_with_prefix("user:");
+        keys.sort();
+        assert_eq!(keys, vec!["user:1", "user:2"]);
+
+        let mut everything = store.list_prefix("");
+        let mut listed = store.list();
+        everything.sort();
+        listed.sort();
+        assert_eq!(everything, listed);
+    }
+
+    #[test]
+    fn common_keys_are_live_in_both_stores() {
+        let clock = manual_clock();
+        let mut ours = KvStore::in_memory().with_clock(clock.clone());
+        let mut theirs = KvStore::in_memory().with_clock(clock.clone());
+        for key in ["a", "b", "c"] {
+            set(&mut ours, key, "1");
+        }
+        set(&mut theirs, "c", "2");
+        set(&mut theirs, "a", "2");
+        set(&mut theirs, "d", "2");
+        theirs.set_with_ttl("b".to_string(), "2".to_string(), Some(1)).unwrap();
+        clock.advance(Duration::from_secs(1));
+
+        assert_eq!(ours.common_keys(&theirs), vec!["a", "c"]);
+        assert_eq!(theirs.common_keys(&ours), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn rename_keeps_the_remaining_ttl() {
+        let clock = manual_clock();
+        let mut store = KvStore::in_memory().with_clock(clock.clone());
+        store.set_with_ttl("from".to_string(), "v".to_string(), Some(60)).unwrap();
+        set(&mut store, "to", "old");
+        clock.advance(Duration::from_secs(20));
+
+        assert!(store.rename("from", "to").unwrap());
+        assert_eq!(store.get("to").as_deref(), Some("v"));
+        assert_eq!(store.get_ttl("to"), Some(40));
+        assert!(!store.contains_key("from"));
+        assert!(!store.rename("from", "elsewhere").unwrap());
+    }
+
+    #[test]
+    fn fail_moves_the_entry_to_the_dlq_at_the_threshold() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "job", "payload");
+
+        assert!(!store.fail("job", 3).unwrap());
+        assert!(!store.fail("job", 3).unwrap());
+        assert!(store.fail("job", 3).unwrap());
+        assert!(!store.contains_key("job"));
+        assert_eq!(store.get("dlq:job").as_deref(), Some("payload"));
+        assert!(store.fail("job", 3).is_err());
+    }
+
+    #[test]
+    fn setting_a_key_again_resets_its_failures() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "job", "payload");
+        assert!(!store.fail("job", 2).unwrap());
+        set(&mut store, "job", "retry");
+        assert!(!store.fail("job", 2).unwrap());
+        store.set_keep_ttl("job".to_string(), "again".to_string()).unwrap();
+        assert!(!store.fail("job", 2).unwrap());
+        assert!(store.contains_key("job"));
+    }
+
+    #[test]
+    fn edits_in_place_keep_the_failure_count() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "n", "1");
+        set(&mut store, "text", "abcdef");
+        set(&mut store, "doc", r#"{"a":1}"#);
+        for key in ["n", "text", "doc"] {
+            assert!(!store.fail(key, 2).unwrap());
+        }
+
+        store.increment("n", 1).unwrap();
+        store.truncate_value("text", 3).unwrap();
+        store.merge_patch("doc", serde_json::json!({"b": 2})).unwrap();
+        for key in ["n", "text", "doc"] {
+            assert_eq!(store.store[key].attempts, 1, "{}", key);
+            assert!(store.fail(key, 2).unwrap());
+        }
+    }
+
+    #[test]
+    fn fail_checks_the_dlq_key_length_before_counting() {
+        let mut store = KvStore::in_memory().with_max_key_bytes(6);
+        set(&mut store, "job123", "payload");
+        assert!(!store.fail("job123", 2).unwrap());
+
+        let Err(e) = store.fail("job123", 2) else { panic!("expected a too-long dlq key") };
+        assert!(e.downcast_ref::<KeyTooLong>().is_some());
+        assert_eq!(store.get("job123").as_deref(), Some("payload"));
+        assert_eq!(store.store["job123"].attempts, 1);
+    }
+
+    #[test]
+    fn expire_and_persist_change_only_the_ttl() {
+        let clock = manual_clock();
+        let mut store = KvStore::in_memory().with_clock(clock.clone());
+        set(&mut store, "k", "v");
+
+        assert!(store.expire("k", 60).unwrap());
+        assert_eq!(store.get_ttl("k"), Some(60));
+        assert!(store.expire("k", 10).unwrap());
+        assert_eq!(store.get_ttl("k"), Some(10));
+        assert!(store.persist("k").unwrap());
+        assert_eq!(store.get_ttl("k"), None);
+        assert_eq!(store.get("k").as_deref(), Some("v"));
+
+        store.expire("k", 1).unwrap();
+        clock.advance(Duration::from_secs(1));
+        assert!(!store.expire("k", 60).unwrap());
+        assert!(!store.persist("k").unwrap());
+        assert!(!store.persist("missing").unwrap());
+    }
+
+    #[test]
+    fn csv_projection_extracts_json_fields() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "u2", r#"{"name":"Bob, Jr.","tags":["x"]}"#);
+        set(&mut store, "u1", r#"{"name":"ada","tags":["a","b"]}"#);
+        set(&mut store, "plain", "text");
+
+        let mut csv = Vec::new();
+        let columns = [Column::Key, Column::JsonPath("name".to_string()), Column::JsonPath("tags.1".to_string()), Column::Ttl];
+        assert_eq!(store.export_csv_projected(&columns, &mut csv).unwrap(), 3);
+        assert_eq!(String::from_utf8(csv).unwrap(), "\
+key,name,tags.1,ttl
+plain,,,
+u1,ada,b,
+u2,\"Bob, Jr.\",,
+");
+    }
+
+    #[test]
+    fn millisecond_ttls_expire_between_seconds() {
+        let clock = manual_clock();
+        let mut store = KvStore::in_memory().with_clock(clock.clone());
+        store.set_with_ttl_ms("k".to_string(), "v".to_string(), Some(250)).unwrap();
+        assert_eq!(store.get_ttl_ms("k"), Some(250));
+        assert_eq!(store.get_ttl("k"), Some(1));
+
+        clock.advance(Duration::from_millis(249));
+        assert_eq!(store.get_ttl_ms("k"), Some(1));
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(store.get("k"), None);
+    }
+
+    #[test]
+    fn seconds_expiries_from_old_files_become_milliseconds() {
+        let dir = TempDir::new();
+        let clock = manual_clock();
+        let now_secs = clock::millis_since_epoch(clock.now()) / 1000;
+        fs::write(dir.json_path(), format!(r#"{{"k":{{"data":"v","expires_at":{}}}}}"#, now_secs + 30)).unwrap();
+
+        let store = KvStore::with_config(StorageConfig { clock, ..dir.config() }).unwrap();
+        assert_eq!(store.get_ttl_ms("k"), Some(30_000));
+    }
+
+    // Backdates `path` past the age at which temp files count as abandoned
+    fn make_stale(path: &Path) {
+        let old = SystemTime::now() - STALE_TEMP_FILE_AGE - Duration::from_secs(1);
+        fs::File::options().write(true).open(path).unwrap().set_modified(old).unwrap();
+    }
+
+    #[test]
+    fn cleanup_temp_files_removes_only_stale_temp_files() {
+        let dir = TempDir::new();
+        let stale = dir.0.join("kv_store.json.1-0.tmp");
+        let fresh = dir.0.join("kv_store.json.2-0.tmp");
+        let unrelated = dir.0.join("notes.tmp");
+        for path in [&stale, &fresh, &unrelated] {
+            fs::write(path, "partial").unwrap();
+        }
+        make_stale(&stale);
+        make_stale(&unrelated);
+
+        assert_eq!(KvStore::cleanup_temp_files(&dir.0).unwrap(), 1);
+        assert!(!stale.exists());
+        assert!(fresh.exists());
+        assert!(unrelated.exists());
+    }
+
+    #[test]
+    fn cleanup_at_startup_is_opt_in() {
+        let dir = TempDir::new();
+        let stale = dir.0.join("kv_store.json.1-0.tmp");
+        fs::write(&stale, "partial").unwrap();
+        make_stale(&stale);
+
+        drop(dir.open());
+        assert!(stale.exists());
+        drop(KvStore::with_config(StorageConfig { cleanup_temp_files: true, ..dir.config() }).unwrap());
+        assert!(!stale.exists());
+    }
+
+    #[test]
+    fn merkle_root_ignores_write_order_and_expired_keys() {
+        let clock = manual_clock();
+        let mut first = KvStore::in_memory().with_clock(clock.clone());
+        let mut second = KvStore::in_memory().with_clock(clock.clone());
+        for (key, value) in [("a", "1"), ("b", "2"), ("c", "3")] {
+            set(&mut first, key, value);
+        }
+        for (key, value) in [("c", "3"), ("a", "1"), ("b", "2")] {
+            set(&mut second, key, value);
+        }
+        second.set_with_ttl("gone".to_string(), "x".to_string(), Some(1)).unwrap();
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(first.merkle_root(), second.merkle_root());
+
+        let proof = first.merkle_proof("b").unwrap();
+        assert!(proof.verify(&second.merkle_root()));
+        set(&mut second, "b", "changed");
+        assert!(!proof.verify(&second.merkle_root()));
+        assert!(first.merkle_proof("gone").is_none());
+    }
+
+    #[test]
+    fn in_memory_store_never_touches_disk() {
+        let clock = manual_clock();
+        let mut store = KvStore::in_memory().with_clock(clock.clone());
+        set(&mut store, "a", "1");
+        store.set_with_ttl("b".to_string(), "2".to_string(), Some(5)).unwrap();
+        assert_eq!(store.get("a").as_deref(), Some("1"));
+        assert_eq!(store.get_ttl("b"), Some(5));
+        assert_eq!(store.delete("a").unwrap().as_deref(), Some("1"));
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(store.cleanup_expired().unwrap(), vec!["b"]);
+        store.flush().unwrap();
+        assert!(store.file_path.is_none());
+        assert_eq!(store.write_bytes_total(), 0);
+        assert_eq!(store.sync_status(), SyncStatus::Synced);
+    }
+
+    #[test]
+    fn merge_patch_follows_rfc_7386() {
+        let mut store = KvStore::in_memory();
+        store.set_with_ttl("cfg".to_string(), r#"{"a":1,"b":{"c":2,"d":3},"e":[1]}"#.to_string(), Some(60)).unwrap();
+
+        store.merge_patch("cfg", serde_json::json!({ "a": null, "b": { "c": 20 }, "e": [2], "f": "new" })).unwrap();
+        let merged: serde_json::Value = serde_json::from_str(&store.get("cfg").unwrap()).unwrap();
+        assert_eq!(merged, serde_json::json!({ "b": { "c": 20, "d": 3 }, "e": [2], "f": "new" }));
+        assert_eq!(store.get_ttl("cfg"), Some(60));
+
+        store.merge_patch("fresh", serde_json::json!({ "x": 1 })).unwrap();
+        assert_eq!(store.get("fresh").as_deref(), Some(r#"{"x":1}"#));
+        set(&mut store, "scalar", "5");
+        store.merge_patch("scalar", serde_json::json!({ "x": 1 })).unwrap();
+        assert_eq!(store.get("scalar").as_deref(), Some(r#"{"x":1}"#));
+    }
+
+    #[test]
+    fn cleanup_expired_returns_removed_keys_sorted() {
+        let dir = TempDir::new();
+        let clock = manual_clock();
+        let mut store = KvStore::with_config(StorageConfig { clock: clock.clone(), ..dir.config() }).unwrap();
+        for key in ["c", "a", "b"] {
+            store.set_with_ttl(key.to_string(), "x".to_string(), Some(1)).unwrap();
+        }
+        set(&mut store, "keep", "x");
+        clock.advance(Duration::from_secs(1));
+
+        assert_eq!(store.cleanup_expired().unwrap(), vec!["a", "b", "c"]);
+        let written = store.write_bytes_total();
+        assert!(store.cleanup_expired().unwrap().is_empty());
+        assert_eq!(store.write_bytes_total(), written);
+        assert_eq!(KvStore::verify_file(dir.json_path()).unwrap().entries, 1);
+    }
+
+    #[test]
+    fn query_filters_sorts_and_pages() {
+        let mut store = KvStore::in_memory();
+        for i in 1..=5 {
+            set(&mut store, &format!("user:{}", i), if i % 2 == 0 { "even" } else { "odd" });
+        }
+        set(&mut store, "post:1", "odd");
+        store.set_with_ttl("user:9".to_string(), "odd".to_string(), Some(10)).unwrap();
+        store.set_with_ttl("user:8".to_string(), "odd".to_string(), Some(5)).unwrap();
+
+        let page = store.query(QueryOptions {
+            prefix: Some("user:".to_string()),
+            value_contains: Some("od".to_string()),
+            offset: 1,
+            limit: Some(2),
+            ..QueryOptions::default()
+        });
+        assert_eq!(page.total, 5);
+        assert_eq!(page.entries, vec![("user:3".to_string(), "odd".to_string()), ("user:5".to_string(), "odd".to_string())]);
+
+        let by_ttl = store.query(QueryOptions { sort: QuerySort::Ttl, limit: Some(3), ..QueryOptions::default() });
+        let keys: Vec<&str> = by_ttl.entries.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, vec!["user:8", "user:9", "post:1"]);
+        assert_eq!(by_ttl.total, 8);
+    }
+
+    #[test]
+    fn decrement_floor_stops_at_the_floor() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "stock", "5");
+        assert_eq!(store.decrement_floor("stock", 3, 0).unwrap(), 2);
+        assert_eq!(store.decrement_floor("stock", 3, 0).unwrap(), 0);
+        assert_eq!(store.decrement_floor("stock", 3, 0).unwrap(), 0);
+
+        set(&mut store, "word", "abc");
+        assert!(store.decrement_floor("word", 1, 0).is_err());
+    }
+
+    #[test]
+    fn decrement_floor_leaves_a_value_below_the_floor_alone() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "stock", "2");
+        assert_eq!(store.decrement_floor("stock", 1, 5).unwrap(), 2);
+        assert_eq!(store.get("stock").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn decrement_floor_starts_a_missing_key_from_zero() {
+        let mut store = KvStore::in_memory();
+        assert_eq!(store.decrement_floor("fresh", 1, 10).unwrap(), 10);
+        assert_eq!(store.decrement_floor("negative", 4, -3).unwrap(), -3);
+        assert_eq!(store.decrement_floor("shallow", 2, -5).unwrap(), -2);
+        assert_eq!(store.get("shallow").as_deref(), Some("-2"));
+    }
+
+    #[test]
+    fn each_format_round_trips_under_its_own_extension() {
+        for (format, file_name) in [(StorageFormat::Json, "kv_store.json"), (StorageFormat::Bincode, "kv_store.bin")] {
+            let dir = TempDir::new();
+            let config = StorageConfig { format, ..dir.config() };
+            let mut store = KvStore::with_config(config.clone()).unwrap();
+            set(&mut store, "k", "v");
+            store.set_with_ttl("t".to_string(), "x".to_string(), Some(60)).unwrap();
+            drop(store);
+
+            let names: Vec<_> = fs::read_dir(&dir.0).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+            assert_eq!(names, vec![file_name]);
+            let reopened = KvStore::with_config(config).unwrap();
+            assert_eq!(reopened.get("k").as_deref(), Some("v"));
+            assert!(reopened.get_ttl("t").is_some());
+        }
+    }
+
+    #[test]
+    fn bincode_files_are_smaller_than_json() {
+        let dir = TempDir::new();
+        let mut sizes = Vec::new();
+        for format in [StorageFormat::Json, StorageFormat::Bincode] {
+            let mut store = KvStore::with_config(StorageConfig { format, ..dir.config() }).unwrap();
+            let entries = (0..1000).map(|i| (format!("key:{}", i), format!("value {}", i), Some(3600))).collect();
+            store.set_many(entries).unwrap();
+            drop(store);
+            sizes.push(fs::metadata(dir.0.join(format.file_name())).unwrap().len());
+        }
+        assert!(sizes[1] < sizes[0], "bincode {} bytes, json {} bytes", sizes[1], sizes[0]);
+    }
+
+    #[test]
+    fn per_key_history_limits_cap_independently() {
+        let mut store = KvStore::in_memory().with_history(3);
+        for i in 1..=5 {
+            set(&mut store, "hot", &i.to_string());
+        }
+        store.set_history_limit("hot", 1);
+        assert_eq!(store.history("hot"), vec!["4"]);
+        store.set_history_limit("quiet", 0);
+
+        for i in 6..=8 {
+            set(&mut store, "hot", &i.to_string());
+            set(&mut store, "quiet", &i.to_string());
+            set(&mut store, "default", &i.to_string());
+        }
+        set(&mut store, "default", "9");
+        assert_eq!(store.history("hot"), vec!["7"]);
+        assert!(store.history("quiet").is_empty());
+        assert_eq!(store.history("default"), vec!["6", "7", "8"]);
+    }
+
+    #[test]
+    fn json_and_csv_exports_round_trip() {
+        let dir = TempDir::new();
+        let mut source = KvStore::in_memory();
+        set(&mut source, "plain", "value");
+        set(&mut source, "tricky", "a, \"quoted\"\nline");
+        set(&mut source, "json", r#"{"a":1}"#);
+
+        for name in ["out.json", "out.csv"] {
+            let path = dir.0.join(name);
+            let json = name.ends_with(".json");
+            let exported = if json { source.export_json(&path) } else { source.export_csv(&path) };
+            assert_eq!(exported.unwrap(), 3);
+
+            let mut target = dir.open();
+            set(&mut target, "plain", "overwritten");
+            let before = target.write_bytes_total();
+            let imported = if json { target.import_json(&path) } else { target.import_csv(&path) };
+            assert_eq!(imported.unwrap(), 3);
+            assert_eq!(target.write_bytes_total() - before, fs::metadata(dir.json_path()).unwrap().len());
+            let mut imported = target.list();
+            let mut expected = source.list();
+            imported.sort();
+            expected.sort();
+            assert_eq!(imported, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn max_key_bytes_allows_keys_up_to_the_limit() {
+        let mut store = KvStore::in_memory().with_max_key_bytes(8);
+        set(&mut store, "12345678", "fits");
+
+        let Err(e) = store.set("123456789".to_string(), "v".to_string()) else { panic!("expected KeyTooLong") };
+        assert_eq!(e.downcast_ref::<KeyTooLong>(), Some(&KeyTooLong { len: 9, max: 8 }));
+        assert!(!store.contains_key("123456789"));
+        assert!(store.rename("12345678", "123456789").is_err());
+        assert_eq!(store.get("12345678").as_deref(), Some("fits"));
+    }
+
+    #[test]
+    fn every_write_path_checks_the_key_limit() {
+        let mut source = KvStore::in_memory();
+        set(&mut source, "long-enough", "v");
+        let mut store = KvStore::in_memory().with_max_key_bytes(8);
+        set(&mut store, "a:1", "v");
+        set(&mut store, "a:2", "v");
+
+        let too_long = |result: Result<usize, Box<dyn Error>>| {
+            result.is_err_and(|e| e.downcast_ref::<KeyTooLong>().is_some())
+        };
+        assert!(too_long(store.prime_from(&source, |_| true)));
+        assert!(too_long(store.reprefix("a:", "much-longer:")));
+        assert!(too_long(store.seed_defaults(&[("ab", "1"), ("far-too-long", "2")])));
+        let change = Change::Set { key: "far-too-long".to_string(), value: "v".to_string(), expires_at: None };
+        assert!(store.apply_change(change).is_err_and(|e| e.downcast_ref::<KeyTooLong>().is_some()));
+
+        // Nothing was written by any of the rejected calls
+        assert_eq!(sorted_keys(&store), vec!["a:1", "a:2"]);
+    }
+
+    #[test]
+    fn modified_between_uses_write_times() {
+        let clock = manual_clock();
+        let start = clock::millis_since_epoch(clock.now());
+        let mut store = KvStore::in_memory().with_clock(clock.clone());
+        set(&mut store, "before", "1");
+        clock.advance(Duration::from_secs(10));
+        set(&mut store, "inside", "2");
+        set(&mut store, "rewritten", "old");
+        clock.advance(Duration::from_secs(10));
+        set(&mut store, "at_end", "3");
+        clock.advance(Duration::from_secs(10));
+        set(&mut store, "rewritten", "new");
+
+        let window = store.modified_between(start + 10_000, start + 20_000);
+        assert_eq!(window, vec![("inside".to_string(), "2".to_string())]);
+        let later = store.modified_between(start + 20_000, u64::MAX);
+        assert_eq!(later, vec![("at_end".to_string(), "3".to_string()), ("rewritten".to_string(), "new".to_string())]);
+    }
+
+    #[test]
+    fn list_limited_flags_truncation() {
+        let clock = manual_clock();
+        let mut store = KvStore::in_memory().with_clock(clock.clone());
+        for i in 0..5 {
+            set(&mut store, &format!("k{}", i), "v");
+        }
+        store.set_with_ttl("gone".to_string(), "x".to_string(), Some(1)).unwrap();
+        clock.advance(Duration::from_secs(1));
+
+        let (entries, truncated) = store.list_limited(3);
+        assert_eq!(entries.len(), 3);
+        assert!(truncated);
+        // The expired entry doesn't count as left out
+        let (entries, truncated) = store.list_limited(5);
+        assert_eq!(entries.len(), 5);
+        assert!(!truncated);
+        assert!(entries.iter().all(|(key, _)| key != "gone"));
+    }
+
+    #[test]
+    fn contains_key_respects_expiry() {
+        let clock = manual_clock();
+        let mut store = KvStore::in_memory().with_clock(clock.clone());
+        set(&mut store, "present", "v");
+        store.set_with_ttl("expiring".to_string(), "v".to_string(), Some(1)).unwrap();
+        assert!(store.contains_key("present"));
+        assert!(store.contains_key("expiring"));
+        assert!(!store.contains_key("absent"));
+
+        clock.advance(Duration::from_secs(1));
+        assert!(!store.contains_key("expiring"));
+    }
+
+    #[test]
+    fn set_if_newer_rejects_older_writes() {
+        let mut store = KvStore::in_memory();
+        assert!(store.set_if_newer("k", "first".to_string(), 1_000).unwrap());
+        assert!(!store.set_if_newer("k", "older".to_string(), 999).unwrap());
+        assert!(!store.set_if_newer("k", "same".to_string(), 1_000).unwrap());
+        assert_eq!(store.get("k").as_deref(), Some("first"));
+
+        assert!(store.set_if_newer("k", "newer".to_string(), 1_001).unwrap());
+        assert_eq!(store.get("k").as_deref(), Some("newer"));
+        assert_eq!(store.modified_between(1_001, 1_002), vec![("k".to_string(), "newer".to_string())]);
+    }
+
+    #[test]
+    fn clear_empties_the_store_on_disk() {
+        let dir = TempDir::new();
+        let mut store = dir.open();
+        for key in ["a", "b", "c"] {
+            set(&mut store, key, "v");
+        }
+        store.clear().unwrap();
+        assert!(store.is_empty());
+        drop(store);
+        assert!(dir.open().is_empty());
+
+        let mut memory = KvStore::in_memory();
+        set(&mut memory, "a", "v");
+        memory.clear().unwrap();
+        assert!(memory.is_empty());
+    }
+
+    #[test]
+    fn export_env_escapes_values() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "db.host", "localhost");
+        set(&mut store, "greeting", "say \"hi\" to $USER\nbye");
+
+        let mut out = Vec::new();
+        assert_eq!(store.export_env("APP", &mut out).unwrap(), 2);
+        assert_eq!(String::from_utf8(out).unwrap(), "\
+APP_DB_HOST=localhost
+APP_GREETING=\"say \\\"hi\\\" to \\$USER\\nbye\"
+");
+    }
+
+    #[test]
+    fn exported_env_loads_back() {
+        let mut store = KvStore::in_memory();
+        set(&mut store, "db_host", "localhost");
+        set(&mut store, "port", "5432");
+        let mut out = Vec::new();
+        store.export_env("KV_EXPORT_ENV_TEST_", &mut out).unwrap();
+
+        // Unquoted lines are valid variable assignments as they stand
+        for line in String::from_utf8(out).unwrap().lines() {
+            let (name, value) = line.split_once('=').unwrap();
+            std::env::set_var(name, value);
+        }
+        let mut loaded = KvStore::in_memory();
+        assert_eq!(loaded.load_env("KV_EXPORT_ENV_TEST").unwrap(), 2);
+        let mut entries = loaded.list();
+        entries.sort();
+        assert_eq!(entries, vec![("db_host".to_string(), "localhost".to_string()), ("port".to_string(), "5432".to_string())]);
+    }
+
+    #[test]
+    fn save_coalescing_skips_saves_in_a_burst() {
+        let dir = TempDir::new();
+        let mut store = dir.open().with_save_coalescing(true);
+        set(&mut store, "k", "0");
+        let one_save = store.write_bytes_total();
+        for i in 1..100 {
+            set(&mut store, "k", &i.to_string());
+        }
+        // Each snapshot is about the same size, so this means fewer saves than sets
+        assert!(store.write_bytes_total() < one_save * 100);
+
+        store.flush().unwrap();
+        assert_eq!(store.sync_status(), SyncStatus::Synced);
+        drop(store);
+        assert_eq!(dir.open().get("k").as_deref(), Some("99"));
+    }
+
+    #[test]
+    fn coalesced_changes_are_pending_until_flushed() {
+        let dir = TempDir::new();
+        let mut store = dir.open().with_save_coalescing(true);
+        set(&mut store, "a", "1");
+        // Pretend the last save was just now, however slow the first one was
+        store.last_save = Some(Instant::now());
+        set(&mut store, "b", "2");
+        assert_eq!(store.sync_status(), SyncStatus::Pending);
+        assert!(!dir.open().contains_key("b"));
+
+        store.flush().unwrap();
+        assert!(dir.open().contains_key("b"));
+    }
+
+    #[test]
+    fn macros_stay_out_of_listings_and_clear() {
+        let dir = TempDir::new();
+        let mut store = dir.open();
+        store.define_macro("m", "set a 1".to_string()).unwrap();
+        set(&mut store, "a", "1");
+
+        assert_eq!(store.list(), vec![("a".to_string(), "1".to_string())]);
+        assert_eq!(store.keys(), vec!["a"]);
+        assert!(store.list_prefix("").iter().all(|(key, _)| key == "a"));
+        assert_eq!(store.len(), 1);
+        let mut env = Vec::new();
+        assert_eq!(store.export_env("APP", &mut env).unwrap(), 1);
+
+        store.clear().unwrap();
+        assert!(store.is_empty());
+        drop(store);
+        assert_eq!(dir.open().macro_body("m").as_deref(), Some("set a 1"));
+    }
+
+    #[test]
+    fn ordinary_writes_refuse_macro_keys() {
+        let mut store = KvStore::in_memory();
+        let key = format!("{}m", MACRO_PREFIX);
+        let Err(e) = store.set(key.clone(), "v".to_string()) else { panic!("expected ReservedKey") };
+        assert_eq!(e.downcast_ref::<ReservedKey>(), Some(&ReservedKey { key: key.clone() }));
+        assert!(store.set_many(vec![(key.clone(), "v".to_string(), None)]).is_err());
+        assert!(store.increment(&key, 1).is_err());
+        set(&mut store, "a", "1");
+        assert!(store.rename("a", &key).is_err());
+        assert_eq!(store.macro_body("m"), None);
+    }
+
+    // Stores values reversed and counts how often they are decoded
+    #[derive(Debug, Default)]
+    struct CountingCodec {
+        decodes: AtomicUsize,
+    }
+
+    impl ValueCodec for CountingCodec {
+        fn encode(&self, value: &str) -> String {
+            value.chars().rev().collect()
+        }
+
+        fn decode(&self, encoded: &str) -> String {
+            self.decodes.fetch_add(1, Ordering::Relaxed);
+            encoded.chars().rev().collect()
+        }
+    }
+
+    #[test]
+    fn values_are_decoded_only_when_read() {
+        let dir = TempDir::new();
+        let codec = Arc::new(CountingCodec::default());
+        let mut store = dir.open().with_codec(codec.clone());
+        set(&mut store, "a", "abc");
+        set(&mut store, "b", "xyz");
+        assert_eq!(store.store["a"].data, "cba");
+
+        let mut keys = store.keys();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "b"]);
+        assert!(store.contains_key("a"));
+        assert_eq!(store.len(), 2);
+        assert_eq!(codec.decodes.load(Ordering::Relaxed), 0);
+
+        assert_eq!(store.get("a").as_deref(), Some("abc"));
+        assert_eq!(codec.decodes.load(Ordering::Relaxed), 1);
+        drop(store);
+
+        let reopened = dir.open().with_codec(codec.clone());
+        assert_eq!(reopened.get("b").as_deref(), Some("xyz"));
+        assert_eq!(codec.decodes.load(Ordering::Relaxed), 2);
+    }
+}
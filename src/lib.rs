@@ -1,3 +1,15 @@
+mod clock;
 mod kv_store;
+mod merged;
+mod merkle;
+mod shared;
+mod types;
+mod watch;
 
-pub use kv_store::KvStore; 
\ No newline at end of file
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use kv_store::{Change, Column, EntryDetail, EntryInfo, ExpiryPolicy, KeepEnd, KeyTooLong, KvStore, LoadReport, MACRO_PREFIX, MergeStrategy, NumericSummary, QueryOptions, QueryResult, QuerySort, ReadView, ReservedKey, Snapshot, StorageFormat, StoreDiff, SyncStatus, TtlMode, ValueCodec, ValueType, VerifyReport};
+pub use merged::MergedView;
+pub use merkle::MerkleProof;
+pub use shared::{ExpirySweeper, Flusher, SharedKvStore};
+pub use types::StorageConfig;
+pub use watch::FileWatcher;
@@ -1,12 +1,38 @@
-use clap::{Parser, Subcommand};
-use simple_kv_store::KvStore;
+use clap::{Parser, Subcommand, ValueEnum};
+use serde_json::json;
+use simple_kv_store::{Change, FileWatcher, KvStore, StorageConfig};
 use std::error::Error;
-use std::io::{self, Write};
+use std::fs;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitCode};
+use std::time::Duration;
+
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
 #[derive(Parser)]
 #[command(name = "kv")]
 #[command(about = "A simple key-value store CLI", long_about = None)]
-struct Cli {}
+#[command(disable_help_subcommand = true)]
+struct Cli {
+    /// Suppress the shell's welcome banner and prompt (also done when stdin
+    /// is not a terminal). For one-shot boolean
+    /// commands, print nothing and report the result only via exit code
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Print results as text or as JSON
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// Run the commands in FILE, one per line, stopping at the first error
+    #[arg(long, value_name = "FILE")]
+    script: Option<PathBuf>,
+
+    /// Run a single command and exit instead of starting the shell
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
 #[derive(Subcommand)]
 enum Command {
@@ -15,24 +41,91 @@ enum Command {
         /// The key to look up
         key: String,
     },
+    /// Check whether a key exists and has not expired
+    Exists {
+        /// The key to check
+        key: String,
+    },
     /// Set a key-value pair
     Set {
         /// The key to set
         key: String,
-        /// The value to set
+        /// The value to set; may start with '-', as in `set n -5`
+        #[arg(allow_hyphen_values = true)]
         value: String,
         /// Optional TTL in seconds
         #[arg(short, long)]
         ttl: Option<u64>,
+        /// Optional TTL in milliseconds
+        #[arg(long, conflicts_with = "ttl")]
+        ttl_ms: Option<u64>,
     },
     /// Delete a key-value pair
     Delete {
         /// The key to delete
         key: String,
     },
+    /// Set several key-value pairs at once
+    Mset {
+        /// Alternating keys and values: <key> <value> [<key> <value> ...]
+        #[arg(required = true, num_args = 2.., allow_hyphen_values = true)]
+        pairs: Vec<String>,
+    },
+    /// Get several values at once
+    Mget {
+        /// The keys to look up
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// Delete several keys at once
+    Mdel {
+        /// The keys to delete
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// Delete every key, asking first when run from a terminal
+    #[command(alias = "flushall")]
+    Clear {
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Rename a key, keeping its value and TTL
+    Rename {
+        /// The key to rename
+        from: String,
+        /// The new name, replacing any existing key
+        to: String,
+    },
     /// List all key-value pairs
-    List,
+    List {
+        /// Print at most this many entries
+        #[arg(short, long)]
+        limit: Option<usize>,
+    },
+    /// Add to the integer stored at a key, treating a missing key as 0
+    Incr {
+        /// The key to increment
+        key: String,
+        /// The amount to add
+        #[arg(default_value_t = 1, allow_negative_numbers = true)]
+        by: i64,
+    },
+    /// Subtract from the integer stored at a key, treating a missing key as 0
+    Decr {
+        /// The key to decrement
+        key: String,
+        /// The amount to subtract
+        #[arg(default_value_t = 1, allow_negative_numbers = true)]
+        by: i64,
+    },
+    /// List the key-value pairs whose key starts with a prefix
+    Scan {
+        /// The key prefix to match
+        prefix: String,
+    },
     /// Get TTL for a key
+    #[command(alias = "getttl")]
     GetTtl {
         /// The key to check TTL for
         key: String,
@@ -42,7 +135,52 @@ enum Command {
         /// The key to check TTL for
         key: String,
     },
+    /// Set a key to expire after a number of seconds, keeping its value
+    Expire {
+        /// The key to expire
+        key: String,
+        /// Seconds until the key expires
+        seconds: u64,
+    },
+    /// Remove a key's TTL so it never expires
+    Persist {
+        /// The key to make permanent
+        key: String,
+    },
+    /// Edit a value in $EDITOR
+    Edit {
+        /// The key to edit
+        key: String,
+    },
+    /// Define a named macro of ';'-separated commands
+    Def {
+        /// The macro name
+        name: String,
+        /// The commands to run, separated by ';'
+        #[arg(required = true, num_args = 1.., trailing_var_arg = true, allow_hyphen_values = true)]
+        body: Vec<String>,
+    },
+    /// Run a previously defined macro
+    Run {
+        /// The macro name
+        name: String,
+    },
+    /// Write all entries to a .json or .csv file, without TTLs
+    Export {
+        /// The file to write; its extension picks the format
+        file: PathBuf,
+    },
+    /// Set every entry from a .json or .csv file, overwriting existing keys
+    Import {
+        /// The file to read; its extension picks the format
+        file: PathBuf,
+    },
+    /// Print changes made by other processes as they happen, until Ctrl-C
+    Tail,
+    /// Verify that persistence works in a temporary store
+    Selftest,
     /// Exit the shell
+    #[command(alias = "quit")]
     Exit,
     /// Show help message
     Help,
@@ -51,128 +189,719 @@ enum Command {
 fn print_help() {
     println!("Available commands:");
     println!("  get <key>                     Get a value by key");
-    println!("  set <key> <value> [--ttl <seconds>]  Set a key-value pair with optional TTL");
+    println!("  exists <key>                  Print whether a key exists");
+    println!("  set <key> <value> [--ttl <seconds> | --ttl-ms <ms>]  Set a key-value pair with optional TTL");
     println!("  delete <key>                  Delete a key-value pair");
-    println!("  list                          List all key-value pairs");
+    println!("  clear [--yes]                 Delete every key");
+    println!("  rename <from> <to>            Rename a key, keeping its TTL");
+    println!("  mset <key> <value> [<key> <value> ...]  Set several key-value pairs");
+    println!("  mget <key> [<key> ...]        Get several values");
+    println!("  mdel <key> [<key> ...]        Delete several keys");
+    println!("  scan <prefix>                 List key-value pairs with keys under a prefix");
+    println!("  incr <key> [<by>]             Add to an integer value (default 1)");
+    println!("  decr <key> [<by>]             Subtract from an integer value (default 1)");
+    println!("  list [--limit <n>]            List all key-value pairs, or the first n");
     println!("  ttl <key>                     Get TTL for a key");
     println!("  getttl <key>                  Get TTL for a key (verbose)");
+    println!("  expire <key> <seconds>        Set a TTL on an existing key");
+    println!("  persist <key>                 Remove a key's TTL");
+    println!("  edit <key>                    Edit a value in $EDITOR");
+    println!("  def <name> <cmd>; <cmd>; ...  Define a macro");
+    println!("  run <name>                    Run a macro");
+    println!("  export <file>                 Write entries to a .json or .csv file");
+    println!("  import <file>                 Set entries from a .json or .csv file");
+    println!("  tail                          Print changes as they happen (Ctrl-C to stop)");
+    println!("  selftest                      Verify persistence in a temporary store");
     println!("  exit                          Exit the shell");
     println!("  help                          Show this help message");
 }
 
-fn parse_input(input: &str) -> Option<Command> {
-    let parts: Vec<&str> = input.trim().split_whitespace().collect();
-    if parts.is_empty() {
-        return None;
-    }
+// A shell line parsed with the same `Command` definitions as the one-shot
+// arguments; `multicall` treats the first word as the subcommand name
+#[derive(Parser)]
+#[command(multicall = true, disable_help_subcommand = true)]
+struct ShellLine {
+    #[command(subcommand)]
+    command: Command,
+}
 
-    match parts[0] {
-        "get" if parts.len() == 2 => Some(Command::Get {
-            key: parts[1].to_string(),
-        }),
-        "set" => {
-            if parts.len() >= 3 {
-                let key = parts[1].to_string();
-                let value = parts[2].to_string();
-                let mut ttl = None;
+/// Parses one shell line into a command. Blank lines are `Ok(None)`.
+/// A `def` keeps the text after its name exactly as typed, quotes
+/// included, so each step is split into words only when the macro runs.
+fn parse_input(input: &str) -> Result<Option<Command>, clap::Error> {
+    let words = split_words(input)
+        .ok_or_else(|| clap::Error::raw(clap::error::ErrorKind::InvalidValue, "unterminated quote\n"))?;
+    if words.is_empty() {
+        return Ok(None);
+    }
+    let body_start = words.get(1).map(|(_, end)| *end);
+    let command = ShellLine::try_parse_from(words.into_iter().map(|(word, _)| word))?.command;
+    Ok(Some(match (command, body_start) {
+        (Command::Def { name, .. }, Some(start)) => Command::Def { name, body: vec![input[start..].trim().to_string()] },
+        (command, _) => command,
+    }))
+}
 
-                if parts.len() >= 5 && parts[3] == "--ttl" {
-                    if let Ok(ttl_val) = parts[4].parse() {
-                        ttl = Some(ttl_val);
+/// Splits a shell line into words the way a POSIX shell would, minus
+/// expansions: `'...'` is taken literally, and inside `"..."` a backslash
+/// escapes `"` or `\`. Each word comes with the byte offset just past its
+/// end. Returns `None` if a quote is left open.
+fn split_words(input: &str) -> Option<Vec<(String, usize)>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take().map(|word| (word, i))),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()?.1 {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()?.1 {
+                        '"' => break,
+                        '\\' => match chars.next()?.1 {
+                            c @ ('"' | '\\') => word.push(c),
+                            c => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                        },
+                        c => word.push(c),
                     }
                 }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word.map(|word| (word, input.len())));
+    Some(words)
+}
 
-                Some(Command::Set { key, value, ttl })
-            } else {
-                println!("Usage: set <key> <value> [--ttl <seconds>]");
-                None
-            }
-        }
-        "delete" if parts.len() == 2 => Some(Command::Delete {
-            key: parts[1].to_string(),
-        }),
-        "list" if parts.len() == 1 => Some(Command::List),
-        "getttl" if parts.len() == 2 => Some(Command::GetTtl {
-            key: parts[1].to_string(),
-        }),
-        "ttl" if parts.len() == 2 => Some(Command::Ttl {
-            key: parts[1].to_string(),
-        }),
-        "exit" | "quit" => Some(Command::Exit),
-        "help" => Some(Command::Help),
-        _ => {
-            println!("Unknown command. Type 'help' for available commands.");
-            None
+// Splits macro text into steps at each `;` outside quotes, with quotes
+// read as `split_words` reads them
+fn split_steps(body: &str) -> Vec<&str> {
+    let mut steps = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    let mut chars = body.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (None, ';') => {
+                steps.push(&body[start..i]);
+                start = i + 1;
+            }
+            (None, '\'' | '"') => quote = Some(c),
+            (Some('"'), '\\') => {
+                chars.next();
+            }
+            (Some(open), c) if c == open => quote = None,
+            _ => {}
         }
     }
+    steps.push(&body[start..]);
+    steps
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let _cli = Cli::parse();
-    let mut store = KvStore::new()?;
-    
-    println!("Welcome to the key-value store shell. Type 'help' for available commands.");
-    
-    loop {
-        print!("> ");
-        io::stdout().flush()?;
-        
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
-        
-        let command = match parse_input(&input) {
-            Some(cmd) => cmd,
-            None => continue,
-        };
+// The one-line summary of a parse error, without clap's usage text. Lines
+// listing missing arguments are folded into the summary
+fn parse_error_summary(e: &clap::Error) -> String {
+    let rendered = e.to_string();
+    let summary: Vec<&str> = rendered.lines().take_while(|line| !line.is_empty()).map(str::trim).collect();
+    let summary = summary.join(" ");
+    summary.strip_prefix("error: ").unwrap_or(&summary).to_string()
+}
+
+/// Asks a yes/no question on the terminal; anything but `y` or `yes` is no.
+fn confirm(question: &str) -> io::Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Opens `current` in `editor` via a temporary file and returns the saved
+/// contents, minus the trailing newline most editors append, or `None` if
+/// the file was left unsaved. `editor` may include arguments, as in
+/// `code --wait`. The file lives in a fresh directory only the user can
+/// read, so other users can't see or swap it.
+fn edit_in_editor(editor: &str, current: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let mut words = editor.split_whitespace();
+    let program = words.next().ok_or("EDITOR is empty")?;
+
+    let dir = std::env::temp_dir().join(format!("kv-edit-{}", uuid::Uuid::new_v4()));
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(&dir)?;
+    let result = edit_file(program, words, &dir.join("value.txt"), current);
+    fs::remove_dir_all(&dir)?;
+    result
+}
+
+// Writes `current` to a new file at `path`, runs the editor on it, and
+// reads it back; `None` if the editor neither changed nor rewrote it
+fn edit_file<'a>(program: &str, args: impl Iterator<Item = &'a str>, path: &Path, current: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).open(path)?;
+    file.write_all(current.as_bytes())?;
+    drop(file);
+    let written = fs::metadata(path)?.modified()?;
+
+    let status = process::Command::new(program).args(args).arg(path).status()?;
+    if !status.success() {
+        return Err(format!("editor '{}' exited with an error", program).into());
+    }
+    let mut edited = fs::read_to_string(path)?;
+    if edited == current && fs::metadata(path)?.modified()? == written {
+        return Ok(None);
+    }
+    if edited.ends_with('\n') {
+        edited.pop();
+    }
+    Ok(Some(edited))
+}
+
+/// Output style for command results.
+#[derive(Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON value per command on stdout, for scripting
+    Json,
+}
 
-        match command {
-            Command::Get { key } => {
-                match store.get(&key) {
-                    Some(value) => println!("{}", value),
-                    None => println!("Key not found"),
+// Where command results go: text lines (misses on stderr) or JSON on stdout
+#[derive(Clone, Copy)]
+struct Output {
+    json: bool,
+}
+
+impl Output {
+    fn print(&self, text: impl std::fmt::Display, value: serde_json::Value) {
+        if self.json {
+            self.print_json(value);
+        } else {
+            println!("{}", text);
+        }
+    }
+
+    fn miss(&self, text: impl std::fmt::Display, value: serde_json::Value) {
+        if self.json {
+            self.print_json(value);
+        } else {
+            eprintln!("{}", text);
+        }
+    }
+
+    fn print_json(&self, value: serde_json::Value) {
+        println!("{}", value);
+    }
+
+    fn error(&self, error: &dyn Error) {
+        if self.json {
+            self.print_json(json!({ "error": error.to_string() }));
+        } else {
+            eprintln!("Error: {}", error);
+        }
+    }
+}
+
+fn entries_json(pairs: &[(String, String)]) -> serde_json::Value {
+    pairs.iter().map(|(key, value)| json!({ "key": key, "value": value })).collect()
+}
+
+enum FileFormat {
+    Json,
+    Csv,
+}
+
+/// Picks the import/export format from a file's extension.
+fn file_format(file: &Path) -> Result<FileFormat, Box<dyn Error>> {
+    match file.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("json") => Ok(FileFormat::Json),
+        Some(ext) if ext.eq_ignore_ascii_case("csv") => Ok(FileFormat::Csv),
+        _ => Err(format!("'{}' must end in .json or .csv", file.display()).into()),
+    }
+}
+
+/// Follows the store file and prints every key set, deleted, or given a
+/// new expiry by any process, without ever writing the file. Runs until
+/// the process is interrupted.
+fn tail(out: Output) -> Result<(), Box<dyn Error>> {
+    let watcher = FileWatcher::start(StorageConfig::default().file_path(), TAIL_POLL_INTERVAL)?;
+    for change in watcher.changes() {
+        match change {
+            Change::Set { key, value, .. } => {
+                out.print(format!("set {} {}", key, value), json!({ "op": "set", "key": key, "value": value }));
+            }
+            Change::Delete { key } => out.print(format!("del {}", key), json!({ "op": "del", "key": key })),
+            Change::Expire { key, expires_at: Some(expires_at) } => out.print(
+                format!("expire {} {}", key, expires_at),
+                json!({ "op": "expire", "key": key, "expires_at": expires_at }),
+            ),
+            Change::Expire { key, expires_at: None } => {
+                out.print(format!("persist {}", key), json!({ "op": "persist", "key": key }));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs a boolean command without printing anything, for `--quiet` one-shot
+/// use. Returns `None` for commands that don't reduce to true/false.
+fn evaluate_quietly(store: &mut KvStore, command: &Command) -> Option<Result<bool, Box<dyn Error>>> {
+    match command {
+        Command::Exists { key } => Some(Ok(store.contains_key(key))),
+        Command::Delete { key } => Some(store.delete(key).map(|deleted| deleted.is_some())),
+        Command::Expire { key, seconds } => Some(store.expire(key, *seconds)),
+        Command::Persist { key } => Some(store.persist(key)),
+        _ => None,
+    }
+}
+
+/// Runs a command against the store. Returns `Ok(false)` when the command
+/// missed (e.g. the key was not found) so one-shot callers can set the exit code.
+fn execute(store: &mut KvStore, command: Command, out: Output) -> Result<bool, Box<dyn Error>> {
+    match command {
+        Command::Get { key } => {
+            match store.get(&key) {
+                Some(value) => out.print(&value, json!({ "found": true, "key": key, "value": value })),
+                None => {
+                    out.miss("Key not found", json!({ "found": false, "key": key }));
+                    return Ok(false);
                 }
             }
-            Command::Set { key, value, ttl } => {
-                store.set_with_ttl(key.clone(), value, ttl)?;
-                println!("Key '{}' has been set.", key);
+        }
+        Command::Exists { key } => {
+            let exists = store.contains_key(&key);
+            out.print(exists, json!({ "exists": exists, "key": key }));
+            return Ok(exists);
+        }
+        Command::Set { key, value, ttl, ttl_ms } => {
+            match ttl_ms {
+                Some(ttl_ms) => store.set_with_ttl_ms(key.clone(), value, Some(ttl_ms))?,
+                None => store.set_with_ttl(key.clone(), value, ttl)?,
             }
-            Command::Delete { key } => {
-                match store.delete(&key)? {
-                    Some(_) => println!("Key '{}' has been deleted.", key),
-                    None => println!("Key not found"),
+            out.print(format!("Key '{}' has been set.", key), json!({ "set": true, "key": key }));
+        }
+        Command::Delete { key } => {
+            match store.delete(&key)? {
+                Some(_) => out.print(format!("Key '{}' has been deleted.", key), json!({ "deleted": true, "key": key })),
+                None => {
+                    out.miss("Key not found", json!({ "deleted": false, "key": key }));
+                    return Ok(false);
                 }
             }
-            Command::List => {
-                let pairs = store.list();
-                if pairs.is_empty() {
-                    println!("Store is empty");
-                } else {
-                    for (key, value) in pairs {
-                        println!("{}: {}", key, value);
+        }
+        Command::Clear { yes } => {
+            let count = store.len();
+            if !yes && !out.json && io::stdin().is_terminal() && !confirm(&format!("Delete all {} keys?", count))? {
+                out.miss("Nothing was deleted.", json!({ "cleared": false }));
+                return Ok(false);
+            }
+            store.clear()?;
+            out.print(format!("{} keys have been deleted.", count), json!({ "cleared": true, "deleted": count }));
+        }
+        Command::Rename { from, to } => {
+            if !store.rename(&from, &to)? {
+                out.miss("Key not found", json!({ "renamed": false, "from": from, "to": to }));
+                return Ok(false);
+            }
+            out.print(
+                format!("Key '{}' has been renamed to '{}'.", from, to),
+                json!({ "renamed": true, "from": from, "to": to }),
+            );
+        }
+        Command::Mset { pairs } => {
+            if pairs.len() % 2 != 0 {
+                return Err("mset takes pairs of keys and values".into());
+            }
+            let count = pairs.len() / 2;
+            let mut pairs = pairs.into_iter();
+            let mut entries = Vec::with_capacity(count);
+            while let (Some(key), Some(value)) = (pairs.next(), pairs.next()) {
+                entries.push((key, value, None));
+            }
+            store.set_many(entries)?;
+            out.print(format!("{} keys have been set.", count), json!({ "set": count }));
+        }
+        Command::Mget { keys } => {
+            let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+            let values = store.get_many(&keys);
+            let all_found = values.iter().all(Option::is_some);
+            if out.json {
+                let entries: Vec<_> = keys
+                    .iter()
+                    .zip(&values)
+                    .map(|(key, value)| json!({ "key": key, "found": value.is_some(), "value": value }))
+                    .collect();
+                out.print_json(json!(entries));
+            } else {
+                for (key, value) in keys.iter().zip(values) {
+                    match value {
+                        Some(value) => println!("{}: {}", key, value),
+                        None => eprintln!("Key '{}' not found", key),
                     }
                 }
             }
-            Command::GetTtl { key } => {
-                match store.get(&key) {
-                    Some(_) => {
-                        match store.get_ttl(&key) {
-                            Some(ttl) => println!("TTL for key '{}': {} seconds", key, ttl),
-                            None => println!("Key '{}' has no TTL set", key),
-                        }
-                    }
-                    None => println!("Key not found"),
+            return Ok(all_found);
+        }
+        Command::Mdel { keys } => {
+            let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+            let removed = store.delete_many(&keys)?;
+            let deleted = removed.iter().filter(|value| value.is_some()).count();
+            out.print(
+                format!("{} of {} keys have been deleted.", deleted, keys.len()),
+                json!({ "deleted": deleted, "requested": keys.len() }),
+            );
+            return Ok(deleted == keys.len());
+        }
+        Command::List { limit } => {
+            let mut pairs = store.list();
+            pairs.sort();
+            let shown = limit.unwrap_or(pairs.len()).min(pairs.len());
+            if out.json {
+                out.print_json(entries_json(&pairs[..shown]));
+            } else if pairs.is_empty() {
+                println!("Store is empty");
+            } else {
+                for (key, value) in &pairs[..shown] {
+                    println!("{}: {}", key, value);
+                }
+                if shown < pairs.len() {
+                    println!("… and {} more", pairs.len() - shown);
+                }
+            }
+        }
+        Command::Scan { prefix } => {
+            let mut pairs = store.list_prefix(&prefix);
+            pairs.sort();
+            if out.json {
+                out.print_json(entries_json(&pairs));
+            } else if pairs.is_empty() {
+                println!("No keys start with '{}'", prefix);
+            } else {
+                for (key, value) in pairs {
+                    println!("{}: {}", key, value);
+                }
+            }
+        }
+        Command::Incr { key, by } => {
+            let value = store.increment(&key, by)?;
+            out.print(value, json!({ "key": key, "value": value }));
+        }
+        Command::Decr { key, by } => {
+            let value = store.decrement(&key, by)?;
+            out.print(value, json!({ "key": key, "value": value }));
+        }
+        Command::GetTtl { key } => {
+            match store.get(&key) {
+                Some(_) => {
+                    let ttl = store.get_ttl(&key);
+                    let text = match ttl {
+                        Some(ttl) => format!("TTL for key '{}': {} seconds", key, ttl),
+                        None => format!("Key '{}' has no TTL set", key),
+                    };
+                    out.print(text, json!({ "found": true, "key": key, "ttl": ttl }));
+                }
+                None => {
+                    out.miss("Key not found", json!({ "found": false, "key": key }));
+                    return Ok(false);
+                }
+            }
+        }
+        Command::Ttl { key } => {
+            match store.get_ttl(&key) {
+                Some(ttl) => out.print(ttl, json!({ "found": true, "key": key, "ttl": ttl })),
+                None => {
+                    out.miss("Key not found or no TTL set", json!({ "found": false, "key": key }));
+                    return Ok(false);
                 }
             }
-            Command::Ttl { key } => {
-                match store.get_ttl(&key) {
-                    Some(ttl) => println!("{}", ttl),
-                    None => println!("Key not found or no TTL set"),
+        }
+        Command::Expire { key, seconds } => {
+            if !store.expire(&key, seconds)? {
+                out.miss("Key not found", json!({ "updated": false, "key": key }));
+                return Ok(false);
+            }
+            out.print(
+                format!("Key '{}' will expire in {} seconds.", key, seconds),
+                json!({ "updated": true, "key": key, "ttl": store.get_ttl(&key) }),
+            );
+        }
+        Command::Persist { key } => {
+            if !store.persist(&key)? {
+                out.miss("Key not found", json!({ "updated": false, "key": key }));
+                return Ok(false);
+            }
+            out.print(format!("Key '{}' no longer expires.", key), json!({ "updated": true, "key": key }));
+        }
+        Command::Edit { key } => {
+            let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+            let current = store.get(&key).unwrap_or_default();
+            let Some(edited) = edit_in_editor(&editor, &current)? else {
+                out.print(format!("Key '{}' was not changed.", key), json!({ "set": false, "key": key }));
+                return Ok(true);
+            };
+            store.set_keep_ttl(key.clone(), edited)?;
+            out.print(format!("Key '{}' has been set.", key), json!({ "set": true, "key": key }));
+        }
+        Command::Def { name, body } => {
+            store.define_macro(&name, body.join(" "))?;
+            out.print(format!("Macro '{}' has been defined.", name), json!({ "defined": true, "macro": name }));
+        }
+        Command::Run { name } => {
+            let Some(body) = store.macro_body(&name) else {
+                out.miss("Macro not found", json!({ "found": false, "macro": name }));
+                return Ok(false);
+            };
+            for (i, step) in split_steps(&body).into_iter().enumerate() {
+                let parsed = parse_input(step)
+                    .map_err(|e| format!("macro '{}' step {}: {}", name, i + 1, parse_error_summary(&e)))?;
+                let Some(command) = parsed else {
+                    continue;
+                };
+                if let Command::Run { .. } | Command::Def { .. } = command {
+                    return Err("macros cannot define or run other macros".into());
+                }
+                if !execute(store, command, out)? {
+                    return Ok(false);
+                }
+            }
+        }
+        Command::Export { file } => {
+            let count = match file_format(&file)? {
+                FileFormat::Json => store.export_json(&file)?,
+                FileFormat::Csv => store.export_csv(&file)?,
+            };
+            out.print(
+                format!("Exported {} keys to '{}'.", count, file.display()),
+                json!({ "exported": count, "file": file }),
+            );
+        }
+        Command::Import { file } => {
+            let count = match file_format(&file)? {
+                FileFormat::Json => store.import_json(&file)?,
+                FileFormat::Csv => store.import_csv(&file)?,
+            };
+            out.print(
+                format!("Imported {} keys from '{}'.", count, file.display()),
+                json!({ "imported": count, "file": file }),
+            );
+        }
+        Command::Tail => tail(out)?,
+        Command::Selftest => {
+            let directory = std::env::temp_dir().join(format!("kv-selftest-{}", process::id()));
+            let result = KvStore::self_test(&directory);
+            if directory.exists() {
+                fs::remove_dir_all(&directory)?;
+            }
+            match result {
+                Ok(()) => out.print("Self-test passed", json!({ "passed": true })),
+                Err(e) => {
+                    out.miss(format!("Self-test failed: {}", e), json!({ "passed": false, "error": e.to_string() }));
+                    return Ok(false);
                 }
             }
-            Command::Help => print_help(),
-            Command::Exit => break,
         }
+        Command::Help => print_help(),
+        Command::Exit => {}
     }
+    Ok(true)
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let out = Output { json: cli.format == OutputFormat::Json };
+    match run(cli, out) {
+        Ok(code) => code,
+        Err(e) => {
+            out.error(e.as_ref());
+            ExitCode::FAILURE
+        }
+    }
+}
 
+/// Runs each line of `script` as a shell command until EOF or `exit`.
+/// Unlike the shell, the first unknown or failing command stops the script.
+fn run_script(store: &mut KvStore, script: impl BufRead, out: Output) -> Result<(), Box<dyn Error>> {
+    for (i, line) in script.lines().enumerate() {
+        let line = line?;
+        let Some(command) = parse_input(&line).map_err(|e| format!("line {}: {}", i + 1, parse_error_summary(&e)))? else {
+            continue;
+        };
+        if let Command::Exit = command {
+            break;
+        }
+        execute(store, command, out).map_err(|e| format!("line {}: {}", i + 1, e))?;
+    }
     Ok(())
-} 
\ No newline at end of file
+}
+
+// Piped input and JSON output have to stay clean, so they get no banner or
+// prompt; `--quiet` turns them off for a terminal too
+fn shows_banner_and_prompt(quiet: bool, json: bool, stdin_is_terminal: bool) -> bool {
+    !quiet && !json && stdin_is_terminal
+}
+
+fn run(cli: Cli, out: Output) -> Result<ExitCode, Box<dyn Error>> {
+    if cli.script.is_some() && cli.command.is_some() {
+        return Err("--script cannot be combined with a command".into());
+    }
+    // Opening the store may rewrite it, which a watcher must never do
+    if let Some(Command::Tail) = cli.command {
+        tail(out)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    let mut store = KvStore::new()?;
+
+    if let Some(command) = cli.command {
+        let quiet_result = if cli.quiet {
+            evaluate_quietly(&mut store, &command)
+        } else {
+            None
+        };
+        let succeeded = match quiet_result {
+            Some(result) => result?,
+            None => execute(&mut store, command, out)?,
+        };
+        return Ok(if succeeded {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        });
+    }
+
+    if let Some(script) = cli.script {
+        let file = fs::File::open(&script).map_err(|e| format!("{}: {}", script.display(), e))?;
+        run_script(&mut store, BufReader::new(file), out)
+            .map_err(|e| format!("{}: {}", script.display(), e))?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let interactive = shows_banner_and_prompt(cli.quiet, out.json, io::stdin().is_terminal());
+    if interactive {
+        println!("Welcome to the key-value store shell. Type 'help' for available commands.");
+    }
+    
+    loop {
+        if interactive {
+            print!("> ");
+            io::stdout().flush()?;
+        }
+        
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input)? == 0 {
+            break;
+        }
+        
+        let command = match parse_input(&input) {
+            Ok(Some(cmd)) => cmd,
+            Ok(None) => continue,
+            Err(e) if out.json => {
+                out.print_json(json!({ "error": parse_error_summary(&e) }));
+                continue;
+            }
+            Err(e) if e.kind() == clap::error::ErrorKind::InvalidSubcommand => {
+                eprintln!("Unknown command. Type 'help' for available commands.");
+                continue;
+            }
+            // Includes `<command> --help`, which clap reports as an error
+            Err(e) => {
+                e.print()?;
+                continue;
+            }
+        };
+
+        if let Command::Exit = command {
+            break;
+        }
+        // A failed command (e.g. a save that couldn't be written) shouldn't end the session
+        if let Err(e) = execute(&mut store, command, out) {
+            out.error(e.as_ref());
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn def_keeps_the_body_as_typed() {
+        let Some(Command::Def { name, body }) = parse_input(r#"def greet  set who "a b";  get who "#).unwrap() else {
+            panic!("expected a def");
+        };
+        assert_eq!(name, "greet");
+        assert_eq!(body, vec![r#"set who "a b";  get who"#]);
+    }
+
+    #[test]
+    fn steps_split_on_semicolons_outside_quotes() {
+        assert_eq!(split_steps(r#"set a "x; y"; get a"#), vec![r#"set a "x; y""#, " get a"]);
+        assert_eq!(split_steps("set a 'it;s'; get a"), vec!["set a 'it;s'", " get a"]);
+        assert_eq!(split_steps(r#"set a "q\";"; get a"#), vec![r#"set a "q\";""#, " get a"]);
+        assert_eq!(split_steps("get a"), vec!["get a"]);
+    }
+
+    #[test]
+    fn quoted_steps_parse_back_to_one_value() {
+        let step = split_steps(r#"set a "x; y"; get a"#)[0];
+        let Some(Command::Set { key, value, .. }) = parse_input(step).unwrap() else {
+            panic!("expected a set");
+        };
+        assert_eq!((key.as_str(), value.as_str()), ("a", "x; y"));
+    }
+
+    #[test]
+    fn parse_errors_summarize_to_one_line() {
+        let Err(error) = parse_input("set") else {
+            panic!("expected a parse error");
+        };
+        assert_eq!(parse_error_summary(&error), "the following required arguments were not provided: <KEY> <VALUE>");
+    }
+
+    #[test]
+    fn words_split_like_a_shell() {
+        let words = |input: &str| split_words(input).map(|words| words.into_iter().map(|(word, _)| word).collect::<Vec<_>>());
+        assert_eq!(words("set k v"), Some(vec!["set".to_string(), "k".to_string(), "v".to_string()]));
+        assert_eq!(words(r#"set k "hello world""#).unwrap()[2], "hello world");
+        assert_eq!(words(r#"set k 'it''s' "a \"b\" \\""#).unwrap()[2..], ["its".to_string(), r#"a "b" \"#.to_string()]);
+        assert_eq!(words(r#"set k "" end"#).unwrap()[2..], [String::new(), "end".to_string()]);
+        assert_eq!(words("set k 'open"), None);
+        assert_eq!(split_words("  get k").unwrap(), vec![("get".to_string(), 5), ("k".to_string(), 7)]);
+    }
+
+    #[test]
+    fn quoted_values_keep_their_spaces_before_a_ttl() {
+        for (line, expected_value, expected_ttl) in [
+            ("set k word", "word", None),
+            (r#"set k "hello world""#, "hello world", None),
+            (r#"set k "hello world" --ttl 30"#, "hello world", Some(30)),
+        ] {
+            let Some(Command::Set { value, ttl, .. }) = parse_input(line).unwrap() else {
+                panic!("expected a set from {:?}", line);
+            };
+            assert_eq!((value.as_str(), ttl), (expected_value, expected_ttl), "{}", line);
+        }
+    }
+
+    #[test]
+    fn only_a_terminal_text_shell_shows_the_banner_and_prompt() {
+        assert!(shows_banner_and_prompt(false, false, true));
+        assert!(!shows_banner_and_prompt(true, false, true));
+        assert!(!shows_banner_and_prompt(false, true, true));
+        assert!(!shows_banner_and_prompt(false, false, false));
+    }
+}
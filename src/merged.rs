@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+use crate::kv_store::{KvStore, Value};
+
+/// Read-only view over several store files (shards) as if they were one
+/// store. Nothing is merged on disk. When more than one shard has a live
+/// entry for a key, the most recently written one wins, by `updated_at`;
+/// ties, including entries from files that predate `updated_at`, go to the
+/// shard listed first.
+#[derive(Debug)]
+pub struct MergedView {
+    shards: Vec<KvStore>,
+}
+
+impl MergedView {
+    /// Loads every shard file in `paths`. The files are only read, never
+    /// written, and a missing or unreadable shard is an error.
+    pub fn open<P: AsRef<Path>>(paths: &[P]) -> Result<Self, Box<dyn Error>> {
+        let shards = paths
+            .iter()
+            .map(|path| KvStore::open_read_only(path.as_ref()))
+            .collect::<Result<_, _>>()?;
+        Ok(MergedView { shards })
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.lookup(key).map(|value| value.data.clone())
+    }
+
+    pub fn list(&self) -> Vec<(String, String)> {
+        let mut winners: HashMap<&str, &Value> = HashMap::new();
+        for shard in &self.shards {
+            for (key, value) in shard.live_entries() {
+                winners
+                    .entry(key)
+                    .and_modify(|best| {
+                        if value.updated_at > best.updated_at {
+                            *best = value;
+                        }
+                    })
+                    .or_insert(value);
+            }
+        }
+        winners
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.data.clone()))
+            .collect()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.lookup(key).is_some()
+    }
+
+    // Winning live entry for `key` across all shards
+    fn lookup(&self, key: &str) -> Option<&Value> {
+        let mut best: Option<&Value> = None;
+        for value in self.shards.iter().filter_map(|shard| shard.live(key)) {
+            if best.is_none_or(|best| value.updated_at > best.updated_at) {
+                best = Some(value);
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv_store::tests::TempDir;
+    use crate::{ManualClock, StorageConfig};
+    use std::fs;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn newest_write_wins_across_shards() {
+        let (first_dir, second_dir) = (TempDir::new(), TempDir::new());
+        let clock = Arc::new(ManualClock::at_millis(1_700_000_000_000));
+        let mut first = KvStore::with_config(StorageConfig { clock: clock.clone(), ..first_dir.config() }).unwrap();
+        let mut second = KvStore::with_config(StorageConfig { clock: clock.clone(), ..second_dir.config() }).unwrap();
+        first.set("only_first".to_string(), "1".to_string()).unwrap();
+        second.set("shared".to_string(), "old".to_string()).unwrap();
+        clock.advance(Duration::from_secs(1));
+        first.set("shared".to_string(), "new".to_string()).unwrap();
+        second.set("only_second".to_string(), "2".to_string()).unwrap();
+        let before = fs::read(first_dir.json_path()).unwrap();
+
+        for paths in [[first_dir.json_path(), second_dir.json_path()], [second_dir.json_path(), first_dir.json_path()]] {
+            let view = MergedView::open(&paths).unwrap();
+            assert_eq!(view.get("shared").as_deref(), Some("new"));
+            assert!(view.contains_key("only_first") && view.contains_key("only_second"));
+            let mut listed = view.list();
+            listed.sort();
+            assert_eq!(listed, vec![
+                ("only_first".to_string(), "1".to_string()),
+                ("only_second".to_string(), "2".to_string()),
+                ("shared".to_string(), "new".to_string()),
+            ]);
+        }
+        assert!(fs::read(first_dir.json_path()).unwrap() == before);
+    }
+
+    #[test]
+    fn ties_go_to_the_first_shard() {
+        let (first_dir, second_dir) = (TempDir::new(), TempDir::new());
+        fs::write(first_dir.json_path(), r#"{"k":{"data":"first","expires_at":null}}"#).unwrap();
+        fs::write(second_dir.json_path(), r#"{"k":{"data":"second","expires_at":null}}"#).unwrap();
+
+        let view = MergedView::open(&[first_dir.json_path(), second_dir.json_path()]).unwrap();
+        assert_eq!(view.get("k").as_deref(), Some("first"));
+        assert_eq!(view.list(), vec![("k".to_string(), "first".to_string())]);
+        assert!(MergedView::open(&[first_dir.0.join("missing.json")]).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use sha2::{Digest, Sha256};
+
+type Hash = [u8; 32];
+
+/// Proof that one entry is part of a store with a given `merkle_root`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub key: String,
+    pub value: String,
+    /// Sibling hashes from the leaf up to the root, hex encoded, each with
+    /// whether the sibling is on the left.
+    pub siblings: Vec<(String, bool)>,
+}
+
+impl MerkleProof {
+    /// Whether this entry hashes up to `root`.
+    pub fn verify(&self, root: &str) -> bool {
+        let mut hash = leaf_hash(&self.key, &self.value);
+        for (sibling, is_left) in &self.siblings {
+            let Some(sibling) = from_hex(sibling) else {
+                return false;
+            };
+            hash = if *is_left { node_hash(&sibling, &hash) } else { node_hash(&hash, &sibling) };
+        }
+        to_hex(&hash) == root
+    }
+}
+
+// Leaves and inner nodes get distinct prefixes so one can't pass for the other
+fn leaf_hash(key: &str, value: &str) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([0u8]);
+    hasher.update((key.len() as u64).to_be_bytes());
+    hasher.update(key.as_bytes());
+    hasher.update(value.as_bytes());
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([1u8]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// Hashes one level into the next; an odd node out is carried up unchanged
+fn next_level(level: &[Hash]) -> Vec<Hash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => node_hash(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Root over `entries`, which must be sorted by key. An empty store hashes
+/// to the SHA-256 of nothing.
+pub(crate) fn root(entries: &[(String, String)]) -> String {
+    let mut level: Vec<Hash> = entries.iter().map(|(key, value)| leaf_hash(key, value)).collect();
+    if level.is_empty() {
+        return to_hex(&Sha256::digest([]).into());
+    }
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    to_hex(&level[0])
+}
+
+/// Proof for `entries[index]`, with `entries` sorted by key.
+pub(crate) fn proof(entries: &[(String, String)], index: usize) -> MerkleProof {
+    let mut level: Vec<Hash> = entries.iter().map(|(key, value)| leaf_hash(key, value)).collect();
+    let mut position = index;
+    let mut siblings = Vec::new();
+    while level.len() > 1 {
+        let sibling = position ^ 1;
+        if sibling < level.len() {
+            siblings.push((to_hex(&level[sibling]), sibling < position));
+        }
+        level = next_level(&level);
+        position /= 2;
+    }
+    let (key, value) = entries[index].clone();
+    MerkleProof { key, value, siblings }
+}
+
+fn to_hex(hash: &Hash) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Hash> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(n: usize) -> Vec<(String, String)> {
+        (0..n).map(|i| (format!("k{:02}", i), format!("v{}", i))).collect()
+    }
+
+    #[test]
+    fn every_proof_verifies_for_odd_and_even_sizes() {
+        for n in 1..=9 {
+            let entries = entries(n);
+            let root = root(&entries);
+            for index in 0..n {
+                assert!(proof(&entries, index).verify(&root), "entry {} of {}", index, n);
+            }
+        }
+    }
+
+    #[test]
+    fn tampered_proofs_fail() {
+        let entries = entries(5);
+        let root = root(&entries);
+        let good = proof(&entries, 2);
+
+        let wrong_value = MerkleProof { value: "other".to_string(), ..good.clone() };
+        assert!(!wrong_value.verify(&root));
+        let mut wrong_side = good.clone();
+        wrong_side.siblings[0].1 = !wrong_side.siblings[0].1;
+        assert!(!wrong_side.verify(&root));
+        let mut bad_hex = good;
+        bad_hex.siblings[0].0 = "zz".to_string();
+        assert!(!bad_hex.verify(&root));
+    }
+}
@@ -0,0 +1,215 @@
+use std::error::Error;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::kv_store::KvStore;
+
+/// A `KvStore` that can be shared between threads. Clones share the same
+/// store. Reads take a read lock and run concurrently; writes take the write
+/// lock and save as usual.
+#[derive(Debug, Clone)]
+pub struct SharedKvStore {
+    inner: Arc<RwLock<KvStore>>,
+}
+
+impl SharedKvStore {
+    pub fn new(store: KvStore) -> Self {
+        SharedKvStore { inner: Arc::new(RwLock::new(store)) }
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.read_lock().get(key)
+    }
+
+    pub fn list(&self) -> Vec<(String, String)> {
+        self.read_lock().list()
+    }
+
+    pub fn get_ttl(&self, key: &str) -> Option<u64> {
+        self.read_lock().get_ttl(key)
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<(), Box<dyn Error>> {
+        self.write_lock().set(key, value)
+    }
+
+    pub fn set_with_ttl(&self, key: String, value: String, ttl_seconds: Option<u64>) -> Result<(), Box<dyn Error>> {
+        self.write_lock().set_with_ttl(key, value, ttl_seconds)
+    }
+
+    pub fn delete(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        self.write_lock().delete(key)
+    }
+
+    /// Runs `f` under the read lock, for any read not wrapped above.
+    pub fn read<R>(&self, f: impl FnOnce(&KvStore) -> R) -> R {
+        f(&self.read_lock())
+    }
+
+    /// Runs `f` under the write lock, so several operations apply without
+    /// other threads seeing the store in between.
+    pub fn write<R>(&self, f: impl FnOnce(&mut KvStore) -> R) -> R {
+        f(&mut self.write_lock())
+    }
+
+    /// Spawns a thread that removes expired entries every `interval`, so a
+    /// long-running process doesn't hold on to dead entries until they are
+    /// next touched. The thread stops when the returned handle is dropped.
+    pub fn start_expiry_sweeper(&self, interval: Duration) -> ExpirySweeper {
+        let store = self.clone();
+        let thread = PeriodicThread::spawn(interval, move || {
+            if let Err(e) = store.write(|store| store.cleanup_expired()) {
+                eprintln!("Expiry sweep failed: {}", e);
+            }
+        });
+        ExpirySweeper { _thread: thread }
+    }
+
+    /// Spawns a thread that calls `flush` every `interval`, so writes held
+    /// in memory by counter buffering, save coalescing, or autosave being
+    /// off reach the disk even if no later write comes along. The thread
+    /// stops when the returned handle is dropped.
+    pub fn start_flusher(&self, interval: Duration) -> Flusher {
+        let store = self.clone();
+        let thread = PeriodicThread::spawn(interval, move || {
+            if let Err(e) = store.write(|store| store.flush()) {
+                eprintln!("Background flush failed: {}", e);
+            }
+        });
+        Flusher { _thread: thread }
+    }
+
+    // A thread that panics while holding the lock only loses its own
+    // operation, so keep serving the store rather than failing every caller
+    fn read_lock(&self) -> RwLockReadGuard<'_, KvStore> {
+        self.inner.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_lock(&self) -> RwLockWriteGuard<'_, KvStore> {
+        self.inner.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Handle to a thread started by `SharedKvStore::start_expiry_sweeper`.
+/// Dropping it stops the thread and waits for it to finish.
+#[derive(Debug)]
+pub struct ExpirySweeper {
+    _thread: PeriodicThread,
+}
+
+/// Handle to a thread started by `SharedKvStore::start_flusher`. Dropping
+/// it stops the thread and waits for it to finish.
+#[derive(Debug)]
+pub struct Flusher {
+    _thread: PeriodicThread,
+}
+
+// Runs a task every interval on its own thread until dropped
+#[derive(Debug)]
+pub(crate) struct PeriodicThread {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PeriodicThread {
+    pub(crate) fn spawn(interval: Duration, mut task: impl FnMut() + Send + 'static) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                task();
+            }
+        });
+        PeriodicThread { stop: Some(stop), thread: Some(thread) }
+    }
+}
+
+impl Drop for PeriodicThread {
+    fn drop(&mut self) {
+        // Closing the channel wakes the thread immediately
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv_store::tests::TempDir;
+    use crate::{ManualClock, StorageConfig, SyncStatus};
+    use std::time::Instant;
+
+    // Polls `done` until it holds, failing after a few seconds
+    fn wait_until(done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn flusher_writes_buffered_counters() {
+        let dir = TempDir::new();
+        let shared = SharedKvStore::new(dir.open().with_counter_buffering(Duration::from_secs(3600)));
+        shared.write(|store| {
+            store.increment("hits", 1).unwrap();
+            store.increment("hits", 1).unwrap();
+        });
+        assert_eq!(shared.read(|store| store.sync_status()), SyncStatus::Pending);
+
+        let _flusher = shared.start_flusher(Duration::from_millis(5));
+        wait_until(|| shared.read(|store| store.sync_status()) == SyncStatus::Synced);
+        assert_eq!(KvStore::with_config(dir.config()).unwrap().get("hits").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn threads_share_one_consistent_store() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SharedKvStore>();
+
+        let shared = SharedKvStore::new(KvStore::in_memory());
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    for i in 0..50 {
+                        shared.set(format!("t{}:{}", t, i), i.to_string()).unwrap();
+                        assert_eq!(shared.get(&format!("t{}:{}", t, i)), Some(i.to_string()));
+                        shared.list();
+                        if i % 2 == 1 {
+                            shared.delete(&format!("t{}:{}", t, i)).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let mut keys: Vec<String> = shared.list().into_iter().map(|(key, _)| key).collect();
+        keys.sort();
+        let mut expected: Vec<String> = (0..8).flat_map(|t| (0..50).step_by(2).map(move |i| format!("t{}:{}", t, i))).collect();
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn sweeper_removes_expired_entries_without_reads() {
+        let dir = TempDir::new();
+        let clock = Arc::new(ManualClock::at_millis(1_700_000_000_000));
+        let store = KvStore::with_config(StorageConfig { clock: clock.clone(), ..dir.config() }).unwrap();
+        let shared = SharedKvStore::new(store);
+        shared.set_with_ttl("session".to_string(), "x".to_string(), Some(1)).unwrap();
+        shared.set("keep".to_string(), "y".to_string()).unwrap();
+
+        let _sweeper = shared.start_expiry_sweeper(Duration::from_millis(5));
+        clock.advance(Duration::from_secs(1));
+        // The sweep saves, so the file shows when the entry left memory
+        wait_until(|| KvStore::verify_file(dir.json_path()).unwrap().entries == 1);
+    }
+}
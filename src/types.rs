@@ -1,22 +1,48 @@
-use serde::{Serialize, Deserialize};
-use serde_json::Value as JsonValue;
 use std::path::PathBuf;
+use std::sync::Arc;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Value {
-    pub data: JsonValue,
-    pub expires_at: Option<u64>,
-}
+use crate::clock::{Clock, SystemClock};
+use crate::kv_store::{StorageFormat, TtlMode};
 
 #[derive(Debug, Clone)]
 pub struct StorageConfig {
     pub directory: PathBuf,
+    /// Encoding of the store file, which is named `kv_store.json` or
+    /// `kv_store.bin` to match.
+    pub format: StorageFormat,
+    /// How expiry times are written, including by the save that drops
+    /// expired entries on open.
+    pub ttl_mode: TtlMode,
+    /// Write entries in key order without write times, as
+    /// `KvStore::with_sorted_keys` does, starting with the save made on open.
+    pub sorted_keys: bool,
+    /// Skip entries that do not parse, counting them in the load report,
+    /// instead of failing the open. The skipped entries are dropped from the
+    /// file by the next save.
+    pub salvage_malformed: bool,
+    /// Remove temp files orphaned by interrupted saves when opening.
+    pub cleanup_temp_files: bool,
+    /// Time source for expiries, including dropping expired entries on open.
+    pub clock: Arc<dyn Clock>,
 }
 
 impl Default for StorageConfig {
     fn default() -> Self {
         Self {
             directory: PathBuf::from("storage"),
+            format: StorageFormat::default(),
+            ttl_mode: TtlMode::default(),
+            sorted_keys: false,
+            salvage_malformed: false,
+            cleanup_temp_files: false,
+            clock: Arc::new(SystemClock),
         }
     }
-} 
\ No newline at end of file
+}
+
+impl StorageConfig {
+    /// Path of the store file this configuration opens.
+    pub fn file_path(&self) -> PathBuf {
+        self.directory.join(self.format.file_name())
+    }
+}
@@ -0,0 +1,115 @@
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, SystemTime};
+
+use crate::kv_store::{Change, KvStore};
+use crate::shared::PeriodicThread;
+
+/// Follows a store file that other processes write, reporting each change
+/// through the same `Change` feed as `KvStore::change_feed`. The file is
+/// only ever read: nothing is expired, rewritten, or created. Dropping the
+/// watcher stops it.
+#[derive(Debug)]
+pub struct FileWatcher {
+    changes: Receiver<Change>,
+    _thread: PeriodicThread,
+}
+
+impl FileWatcher {
+    /// Loads `path` and then checks it every `interval`, reloading it
+    /// whenever its size or modification time changes. Only changes after
+    /// the first load are reported; keys that expire are reported as
+    /// deleted. A missing file counts as an empty store.
+    pub fn start<P: Into<PathBuf>>(path: P, interval: Duration) -> Result<Self, Box<dyn Error>> {
+        let path = path.into();
+        let mut stamp = file_stamp(&path)?;
+        let mut mirror = load(&path, stamp)?;
+        let changes = mirror.change_feed_from_now();
+
+        let thread = PeriodicThread::spawn(interval, move || {
+            // A file caught mid-replace or briefly unreadable is retried on the next tick
+            if let Ok(current) = file_stamp(&path) {
+                if current != stamp {
+                    if let Ok(store) = load(&path, current) {
+                        mirror.replace_entries(store);
+                        stamp = current;
+                    }
+                }
+            }
+            mirror.publish_changes();
+        });
+        Ok(FileWatcher { changes, _thread: thread })
+    }
+
+    /// Changes seen so far and any still to come, in the order they were found.
+    pub fn changes(&self) -> &Receiver<Change> {
+        &self.changes
+    }
+}
+
+// Size and modification time of the file, or `None` if it doesn't exist
+fn file_stamp(path: &Path) -> io::Result<Option<(u64, SystemTime)>> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(Some((metadata.len(), metadata.modified()?))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn load(path: &Path, stamp: Option<(u64, SystemTime)>) -> Result<KvStore, Box<dyn Error>> {
+    match stamp {
+        Some(_) => KvStore::open_read_only(path),
+        None => Ok(KvStore::in_memory()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv_store::tests::TempDir;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn reports_changes_written_by_another_store() {
+        let dir = TempDir::new();
+        let mut writer = dir.open();
+        writer.set("before".to_string(), "1".to_string()).unwrap();
+
+        let watcher = FileWatcher::start(dir.json_path(), Duration::from_millis(5)).unwrap();
+        writer.set("after".to_string(), "2".to_string()).unwrap();
+        assert_eq!(watcher.changes().recv_timeout(WAIT).unwrap(), Change::Set {
+            key: "after".to_string(),
+            value: "2".to_string(),
+            expires_at: None,
+        });
+
+        writer.delete("before").unwrap();
+        assert_eq!(watcher.changes().recv_timeout(WAIT).unwrap(), Change::Delete { key: "before".to_string() });
+    }
+
+    #[test]
+    fn never_writes_the_file() {
+        let dir = TempDir::new();
+        fs::write(dir.json_path(), r#"{"old":{"data":"x","expires_at_ms":1}}"#).unwrap();
+        let before = fs::read(dir.json_path()).unwrap();
+
+        let watcher = FileWatcher::start(dir.json_path(), Duration::from_millis(5)).unwrap();
+        assert!(watcher.changes().recv_timeout(Duration::from_millis(50)).is_err());
+        drop(watcher);
+        assert_eq!(fs::read(dir.json_path()).unwrap(), before);
+    }
+
+    #[test]
+    fn treats_a_missing_file_as_empty() {
+        let dir = TempDir::new();
+        let watcher = FileWatcher::start(dir.json_path(), Duration::from_millis(5)).unwrap();
+        assert!(!dir.json_path().exists());
+
+        dir.open().set("k".to_string(), "v".to_string()).unwrap();
+        assert!(matches!(watcher.changes().recv_timeout(WAIT).unwrap(), Change::Set { key, .. } if key == "k"));
+    }
+}
@@ -0,0 +1,523 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// A scratch working directory for one test; the CLI keeps its store in
+// `./storage` under it. Removed when dropped.
+struct Workdir(PathBuf);
+
+impl Workdir {
+    fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!("kv-cli-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(&path).unwrap();
+        Workdir(path)
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_kv"));
+        command.current_dir(&self.0);
+        command
+    }
+
+    fn kv(&self, args: &[&str]) -> Output {
+        self.command().args(args).output().unwrap()
+    }
+
+    // Runs the shell with `input` piped to stdin
+    fn shell(&self, args: &[&str], input: &str) -> Output {
+        let mut child = self.command()
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+        child.wait_with_output().unwrap()
+    }
+
+    fn store_file(&self) -> PathBuf {
+        self.0.join("storage").join("kv_store.json")
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for Workdir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn modified(path: &Path) -> std::time::SystemTime {
+    fs::metadata(path).unwrap().modified().unwrap()
+}
+
+fn assert_success(output: &Output) {
+    assert!(output.status.success(), "kv failed: {}", stderr(output));
+}
+
+#[test]
+fn get_of_missing_key_exits_with_failure() {
+    let dir = Workdir::new();
+    assert_success(&dir.kv(&["set", "k", "v"]));
+
+    let found = dir.kv(&["get", "k"]);
+    assert_success(&found);
+    assert_eq!(stdout(&found), "v\n");
+
+    let missing = dir.kv(&["get", "nope"]);
+    assert_eq!(missing.status.code(), Some(1));
+    assert_eq!(stdout(&missing), "");
+    assert_eq!(stderr(&missing), "Key not found\n");
+}
+
+#[test]
+fn missing_key_in_json_mode_reports_found_false() {
+    let dir = Workdir::new();
+    let missing = dir.kv(&["--format", "json", "get", "nope"]);
+    assert_eq!(missing.status.code(), Some(1));
+    assert_eq!(stdout(&missing), "{\"found\":false,\"key\":\"nope\"}\n");
+}
+
+#[test]
+fn shell_keeps_going_after_a_miss() {
+    let dir = Workdir::new();
+    let output = dir.shell(&[], "get nope\nset k v\nget k\n");
+    assert_success(&output);
+    assert_eq!(stdout(&output), "Key 'k' has been set.\nv\n");
+    assert_eq!(stderr(&output), "Key not found\n");
+}
+
+// An editor command that runs a shell script with the file to edit as `$1`
+fn editor_script(dir: &Workdir, body: &str) -> String {
+    let script = dir.path("editor.sh");
+    fs::write(&script, body).unwrap();
+    format!("sh {}", script.display())
+}
+
+#[test]
+fn edit_saves_what_the_editor_wrote_and_keeps_the_ttl() {
+    let dir = Workdir::new();
+    assert_success(&dir.kv(&["set", "k", "old", "--ttl", "100"]));
+    let editor = editor_script(&dir, "test \"$(cat \"$1\")\" = old && printf 'new\\n' > \"$1\"\n");
+
+    let output = dir.command().args(["edit", "k"]).env("EDITOR", editor).output().unwrap();
+    assert_success(&output);
+    assert_eq!(stdout(&dir.kv(&["get", "k"])), "new\n");
+    let ttl: u64 = stdout(&dir.kv(&["ttl", "k"])).trim().parse().unwrap();
+    assert!(ttl > 0 && ttl <= 100);
+}
+
+#[test]
+fn edit_quit_without_saving_does_not_create_the_key() {
+    let dir = Workdir::new();
+    let output = dir.command().args(["edit", "k"]).env("EDITOR", "true").output().unwrap();
+    assert_success(&output);
+    assert_eq!(stdout(&output), "Key 'k' was not changed.\n");
+    assert_eq!(dir.kv(&["exists", "k"]).status.code(), Some(1));
+}
+
+#[cfg(unix)]
+#[test]
+fn edit_uses_a_private_temp_directory() {
+    let dir = Workdir::new();
+    let log = dir.path("mode");
+    let editor = editor_script(&dir, &format!("stat -c %a \"$(dirname \"$1\")\" > {}\necho x > \"$1\"\n", log.display()));
+
+    assert_success(&dir.command().args(["edit", "k"]).env("EDITOR", editor).output().unwrap());
+    assert_eq!(fs::read_to_string(log).unwrap(), "700\n");
+}
+
+#[test]
+fn edit_reports_a_failing_editor() {
+    let dir = Workdir::new();
+    let output = dir.command().args(["edit", "k"]).env("EDITOR", "false").output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("exited with an error"));
+}
+
+#[test]
+fn macros_keep_quoted_values_and_semicolons() {
+    let dir = Workdir::new();
+    let output = dir.shell(&[], "def m set a \"x; y\"; get a\nrun m\n");
+    assert_success(&output);
+    assert_eq!(stdout(&output), "Macro 'm' has been defined.\nKey 'a' has been set.\nx; y\n");
+}
+
+#[test]
+fn macros_defined_from_arguments_run_later() {
+    let dir = Workdir::new();
+    assert_success(&dir.kv(&["def", "m", "set b 'p q'; get b"]));
+    let output = dir.kv(&["run", "m"]);
+    assert_success(&output);
+    assert_eq!(stdout(&output), "Key 'b' has been set.\np q\n");
+}
+
+#[test]
+fn list_and_clear_leave_macros_alone() {
+    let dir = Workdir::new();
+    assert_success(&dir.kv(&["def", "m", "set b 1"]));
+    assert_success(&dir.kv(&["set", "a", "1"]));
+    assert_eq!(stdout(&dir.kv(&["list"])), "a: 1\n");
+
+    assert_success(&dir.kv(&["clear", "--yes"]));
+    assert_eq!(stdout(&dir.kv(&["run", "m"])), "Key 'b' has been set.\n");
+    let refused = dir.kv(&["set", "__macro__:m", "x"]);
+    assert_eq!(refused.status.code(), Some(1));
+}
+
+#[test]
+fn macro_parse_errors_are_one_line() {
+    let dir = Workdir::new();
+    assert_success(&dir.kv(&["def", "bad", "get a; set"]));
+    let output = dir.kv(&["run", "bad"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), "Key not found\n");
+
+    assert_success(&dir.kv(&["set", "a", "1"]));
+    let output = dir.kv(&["run", "bad"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "1\n");
+    assert_eq!(stderr(&output), "Error: macro 'bad' step 2: the following required arguments were not provided: <KEY> <VALUE>\n");
+}
+
+#[test]
+fn macros_cannot_run_macros() {
+    let dir = Workdir::new();
+    assert_success(&dir.kv(&["def", "inner", "get a"]));
+    assert_success(&dir.kv(&["def", "outer", "run inner"]));
+    let output = dir.kv(&["run", "outer"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("macros cannot define or run other macros"));
+}
+
+#[test]
+fn selftest_command_passes() {
+    let dir = Workdir::new();
+    let output = dir.kv(&["selftest"]);
+    assert_success(&output);
+    assert_eq!(stdout(&output), "Self-test passed\n");
+
+    let json = dir.kv(&["--format", "json", "selftest"]);
+    assert_eq!(stdout(&json), "{\"passed\":true}\n");
+}
+
+#[test]
+fn piped_shell_prints_only_command_output() {
+    let dir = Workdir::new();
+    let output = dir.shell(&[], "set k v\nget k\nexit\nget k\n");
+    assert_success(&output);
+    assert_eq!(stdout(&output), "Key 'k' has been set.\nv\n");
+}
+
+#[test]
+fn quiet_boolean_commands_answer_with_the_exit_code() {
+    let dir = Workdir::new();
+    assert_success(&dir.kv(&["set", "k", "v"]));
+
+    for (args, code) in [
+        (&["-q", "exists", "k"][..], 0),
+        (&["-q", "exists", "nope"][..], 1),
+        (&["-q", "expire", "k", "100"][..], 0),
+        (&["-q", "persist", "k"][..], 0),
+        (&["-q", "persist", "nope"][..], 1),
+        (&["-q", "delete", "k"][..], 0),
+        (&["-q", "delete", "k"][..], 1),
+    ] {
+        let output = dir.kv(args);
+        assert_eq!(output.status.code(), Some(code), "kv {:?}", args);
+        assert_eq!(stdout(&output), "", "kv {:?}", args);
+        assert_eq!(stderr(&output), "", "kv {:?}", args);
+    }
+}
+
+#[test]
+fn reads_do_not_rewrite_the_store_file() {
+    let dir = Workdir::new();
+    assert_success(&dir.kv(&["set", "k", "v", "--ttl", "100"]));
+    let before = modified(&dir.store_file());
+    std::thread::sleep(std::time::Duration::from_millis(20));
+
+    assert_success(&dir.kv(&["get", "k"]));
+    assert_success(&dir.kv(&["list"]));
+    assert_eq!(modified(&dir.store_file()), before);
+}
+
+#[test]
+fn tail_prints_changes_from_other_processes_without_writing() {
+    let dir = Workdir::new();
+    fs::create_dir_all(dir.store_file().parent().unwrap()).unwrap();
+    fs::write(dir.store_file(), r#"{"old":{"data":"x","expires_at_ms":1}}"#).unwrap();
+
+    let mut tail = dir.command().arg("tail").stdout(Stdio::piped()).spawn().unwrap();
+    let (lines, received) = std::sync::mpsc::channel();
+    let stdout = tail.stdout.take().unwrap();
+    std::thread::spawn(move || {
+        for line in std::io::BufRead::lines(std::io::BufReader::new(stdout)) {
+            if lines.send(line.unwrap()).is_err() {
+                break;
+            }
+        }
+    });
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    let untouched = fs::read(dir.store_file()).unwrap();
+    assert!(String::from_utf8_lossy(&untouched).contains("\"old\""));
+
+    assert_success(&dir.kv(&["set", "k", "v"]));
+    let line = received.recv_timeout(std::time::Duration::from_secs(5));
+    tail.kill().unwrap();
+    tail.wait().unwrap();
+    assert_eq!(line.unwrap(), "set k v");
+}
+
+#[test]
+fn list_limit_truncates_with_a_notice() {
+    let dir = Workdir::new();
+    assert_success(&dir.kv(&["mset", "a", "1", "b", "2", "c", "3", "d", "4", "e", "5"]));
+
+    let limited = dir.kv(&["list", "--limit", "2"]);
+    assert_success(&limited);
+    assert_eq!(stdout(&limited), "a: 1\nb: 2\n… and 3 more\n");
+
+    let all = dir.kv(&["list", "--limit", "10"]);
+    assert_eq!(stdout(&all), "a: 1\nb: 2\nc: 3\nd: 4\ne: 5\n");
+}
+
+#[test]
+fn unwritable_storage_is_reported_not_a_panic() {
+    let dir = Workdir::new();
+    // A file where the storage directory should be
+    fs::write(dir.path("storage"), "").unwrap();
+
+    let output = dir.kv(&["set", "k", "v"]);
+    assert!(!output.status.success());
+    assert!(!stderr(&output).contains("panicked"), "{}", stderr(&output));
+    assert!(stderr(&output).starts_with("Error: "), "{}", stderr(&output));
+}
+
+#[test]
+fn batch_commands_report_per_key_results() {
+    let dir = Workdir::new();
+    assert_success(&dir.kv(&["mset", "a", "1", "b", "2"]));
+
+    let found = dir.kv(&["mget", "a", "missing", "b"]);
+    assert_eq!(found.status.code(), Some(1));
+    assert_eq!(stdout(&found), "a: 1\nb: 2\n");
+    assert_eq!(stderr(&found), "Key 'missing' not found\n");
+
+    let deleted = dir.kv(&["mdel", "a", "b"]);
+    assert_success(&deleted);
+    assert_eq!(stdout(&deleted), "2 of 2 keys have been deleted.\n");
+
+    assert!(!dir.kv(&["mset", "a"]).status.success());
+}
+
+#[test]
+fn incr_and_decr_accept_negative_amounts() {
+    let dir = Workdir::new();
+    assert_eq!(stdout(&dir.kv(&["incr", "n"])), "1\n");
+    assert_eq!(stdout(&dir.kv(&["incr", "n", "-5"])), "-4\n");
+    assert_eq!(stdout(&dir.kv(&["decr", "n", "-10"])), "6\n");
+
+    assert_success(&dir.kv(&["set", "word", "abc"]));
+    let failed = dir.kv(&["incr", "word"]);
+    assert!(!failed.status.success());
+    assert!(stderr(&failed).contains("not an integer"), "{}", stderr(&failed));
+}
+
+#[test]
+fn scan_lists_keys_under_a_prefix() {
+    let dir = Workdir::new();
+    assert_success(&dir.kv(&["mset", "user:2", "bob", "post:1", "hi", "user:1", "ada"]));
+
+    assert_eq!(stdout(&dir.kv(&["scan", "user:"])), "user:1: ada\nuser:2: bob\n");
+    assert_eq!(stdout(&dir.kv(&["scan", "tag:"])), "No keys start with 'tag:'\n");
+}
+
+#[test]
+fn rename_moves_a_key_and_reports_misses() {
+    let dir = Workdir::new();
+    assert_success(&dir.kv(&["set", "from", "v", "--ttl", "60"]));
+
+    let renamed = dir.kv(&["rename", "from", "to"]);
+    assert_success(&renamed);
+    assert_eq!(stdout(&renamed), "Key 'from' has been renamed to 'to'.\n");
+    assert_eq!(stdout(&dir.kv(&["get", "to"])), "v\n");
+    assert!(dir.kv(&["ttl", "to"]).status.success());
+
+    let missing = dir.kv(&["rename", "from", "to"]);
+    assert_eq!(missing.status.code(), Some(1));
+    assert_eq!(stderr(&missing), "Key not found\n");
+}
+
+#[test]
+fn expire_and_persist_commands() {
+    let dir = Workdir::new();
+    assert_success(&dir.kv(&["set", "k", "v"]));
+
+    let expired = dir.kv(&["expire", "k", "100"]);
+    assert_success(&expired);
+    assert_eq!(stdout(&expired), "Key 'k' will expire in 100 seconds.\n");
+    assert_eq!(stdout(&dir.kv(&["ttl", "k"])), "100\n");
+
+    assert_eq!(stdout(&dir.kv(&["persist", "k"])), "Key 'k' no longer expires.\n");
+    assert_eq!(dir.kv(&["ttl", "k"]).status.code(), Some(1));
+    assert_eq!(dir.kv(&["expire", "missing", "5"]).status.code(), Some(1));
+    assert_eq!(dir.kv(&["persist", "missing"]).status.code(), Some(1));
+}
+
+#[test]
+fn set_accepts_a_ttl_in_milliseconds() {
+    let dir = Workdir::new();
+    assert_success(&dir.kv(&["set", "k", "v", "--ttl-ms", "100000"]));
+    assert_eq!(stdout(&dir.kv(&["ttl", "k"])), "100\n");
+
+    assert_success(&dir.kv(&["set", "short", "v", "--ttl-ms", "1"]));
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    assert_eq!(dir.kv(&["get", "short"]).status.code(), Some(1));
+}
+
+#[test]
+fn export_and_import_pick_the_format_from_the_extension() {
+    let dir = Workdir::new();
+    assert_success(&dir.kv(&["mset", "a", "x,y", "b", "say \"hi\""]));
+
+    for file in ["dump.json", "dump.csv"] {
+        let exported = dir.kv(&["export", file]);
+        assert_success(&exported);
+        assert_eq!(stdout(&exported), format!("Exported 2 keys to '{}'.\n", file));
+
+        assert_success(&dir.kv(&["mdel", "a", "b"]));
+        assert_eq!(stdout(&dir.kv(&["import", file])), format!("Imported 2 keys from '{}'.\n", file));
+        assert_eq!(stdout(&dir.kv(&["list"])), "a: x,y\nb: say \"hi\"\n");
+    }
+
+    let unknown = dir.kv(&["export", "dump.txt"]);
+    assert!(!unknown.status.success());
+    assert!(stderr(&unknown).contains("must end in .json or .csv"));
+}
+
+// Runs a one-shot command with `--format json` and parses what it printed
+fn kv_json(dir: &Workdir, args: &[&str]) -> serde_json::Value {
+    let output = dir.kv(&[&["--format", "json"], args].concat());
+    serde_json::from_slice(&output.stdout).unwrap_or_else(|e| panic!("{}: {}", e, stdout(&output)))
+}
+
+#[test]
+fn json_mode_emits_parseable_objects() {
+    let dir = Workdir::new();
+    assert_eq!(kv_json(&dir, &["set", "k", "v"]), serde_json::json!({ "set": true, "key": "k" }));
+    assert_eq!(kv_json(&dir, &["get", "k"]), serde_json::json!({ "found": true, "key": "k", "value": "v" }));
+    assert_eq!(kv_json(&dir, &["set", "a", "1"])["set"], true);
+    assert_eq!(kv_json(&dir, &["list"]), serde_json::json!([
+        { "key": "a", "value": "1" },
+        { "key": "k", "value": "v" },
+    ]));
+    assert_eq!(kv_json(&dir, &["delete", "k"]), serde_json::json!({ "deleted": true, "key": "k" }));
+
+    assert_eq!(kv_json(&dir, &["incr", "a"]), serde_json::json!({ "key": "a", "value": 2 }));
+    assert_success(&dir.kv(&["set", "word", "abc"]));
+    let error = kv_json(&dir, &["incr", "word"]);
+    assert!(error["error"].as_str().unwrap().contains("not an integer"));
+}
+
+#[test]
+fn script_runs_every_line_and_stops_at_the_first_error() {
+    let dir = Workdir::new();
+    fs::write(dir.path("ok.txt"), "set a 1\n\nset b 2\ndelete a\n").unwrap();
+    let output = dir.kv(&["--script", "ok.txt"]);
+    assert_success(&output);
+    assert_eq!(stdout(&output), "Key 'a' has been set.\nKey 'b' has been set.\nKey 'a' has been deleted.\n");
+    assert_eq!(stdout(&dir.kv(&["list"])), "b: 2\n");
+
+    fs::write(dir.path("bad.txt"), "set c 3\nincr b x\nset d 4\n").unwrap();
+    let failed = dir.kv(&["--script", "bad.txt"]);
+    assert!(!failed.status.success());
+    assert!(stderr(&failed).starts_with("Error: bad.txt: line 2: "), "{}", stderr(&failed));
+    assert_eq!(stdout(&dir.kv(&["list"])), "b: 2\nc: 3\n");
+}
+
+#[test]
+fn script_cannot_be_combined_with_a_command() {
+    let dir = Workdir::new();
+    fs::write(dir.path("s.txt"), "set a 1\n").unwrap();
+    let output = dir.kv(&["--script", "s.txt", "get", "a"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("--script cannot be combined with a command"));
+    assert!(!dir.store_file().exists());
+}
+
+#[test]
+fn piped_commands_leave_the_expected_store() {
+    let dir = Workdir::new();
+    let output = dir.shell(&[], "mset a 1 b 2\nincr a 5\ndelete b\n");
+    assert_success(&output);
+    assert_eq!(stdout(&output), "2 keys have been set.\n6\nKey 'b' has been deleted.\n");
+    assert_eq!(stdout(&dir.kv(&["list"])), "a: 6\n");
+}
+
+#[test]
+fn subcommands_run_once_and_exit() {
+    let dir = Workdir::new();
+    let set = dir.kv(&["set", "foo", "bar", "--ttl", "30"]);
+    assert_success(&set);
+    assert_eq!(stdout(&set), "Key 'foo' has been set.\n");
+    assert_eq!(stdout(&dir.kv(&["get", "foo"])), "bar\n");
+    assert_eq!(stdout(&dir.kv(&["ttl", "foo"])), "30\n");
+
+    assert_success(&dir.kv(&["set", "n", "-5"]));
+    assert_eq!(stdout(&dir.kv(&["get", "n"])), "-5\n");
+
+    let unknown = dir.kv(&["frobnicate"]);
+    assert_eq!(unknown.status.code(), Some(2));
+}
+
+#[test]
+fn shell_values_can_be_quoted() {
+    let dir = Workdir::new();
+    let output = dir.shell(&[], "set greeting \"hello world\" --ttl 30\nget greeting\nttl greeting\n");
+    assert_success(&output);
+    assert_eq!(stdout(&output), "Key 'greeting' has been set.\nhello world\n30\n");
+}
+
+#[test]
+fn exists_prints_true_or_false() {
+    let dir = Workdir::new();
+    assert_success(&dir.kv(&["set", "k", "v"]));
+
+    let present = dir.kv(&["exists", "k"]);
+    assert_success(&present);
+    assert_eq!(stdout(&present), "true\n");
+    let absent = dir.kv(&["exists", "nope"]);
+    assert_eq!(absent.status.code(), Some(1));
+    assert_eq!(stdout(&absent), "false\n");
+}
+
+#[test]
+fn clear_and_flushall_wipe_the_store() {
+    let dir = Workdir::new();
+    assert_success(&dir.kv(&["mset", "a", "1", "b", "2"]));
+    let cleared = dir.kv(&["clear", "--yes"]);
+    assert_success(&cleared);
+    assert_eq!(stdout(&cleared), "2 keys have been deleted.\n");
+    assert_eq!(stdout(&dir.kv(&["list"])), "Store is empty\n");
+
+    assert_success(&dir.kv(&["set", "c", "3"]));
+    // stdin isn't a terminal here, so there is no prompt to answer
+    assert_success(&dir.kv(&["flushall"]));
+    assert_eq!(stdout(&dir.kv(&["list"])), "Store is empty\n");
+}
@@ -0,0 +1,26 @@
+use simple_kv_store::KvStore;
+use std::fs;
+
+// `KvStore::new` keeps its data in `./storage`, so this is the only test in
+// this binary: nothing else may change the working directory under it.
+#[test]
+fn new_round_trips_through_the_default_directory() {
+    let dir = std::env::temp_dir().join(format!("kv-default-store-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    std::env::set_current_dir(&dir).unwrap();
+
+    let mut store = KvStore::new().unwrap();
+    store.set("plain".to_string(), "value".to_string()).unwrap();
+    store.set("json".to_string(), r#"{"a":[1,2]}"#.to_string()).unwrap();
+    store.set_with_ttl("ttl".to_string(), "x".to_string(), Some(60)).unwrap();
+    drop(store);
+    assert!(dir.join("storage").join("kv_store.json").exists());
+
+    let reopened = KvStore::new().unwrap();
+    assert_eq!(reopened.get("plain").as_deref(), Some("value"));
+    assert_eq!(reopened.get("json").as_deref(), Some(r#"{"a":[1,2]}"#));
+    assert!(reopened.get_ttl("ttl").is_some_and(|ttl| ttl > 0 && ttl <= 60));
+    drop(reopened);
+
+    let _ = fs::remove_dir_all(&dir);
+}